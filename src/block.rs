@@ -0,0 +1,198 @@
+//! A memoizer that caches whole blocks of values, such as the rows of a grid.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use crate::guard::{fmt_memoizer, guarded};
+use crate::{MemoStruct, MemoVal};
+
+type BlockOf<'a, K, B> = dyn 'a + Fn(&K) -> (B, usize);
+type BlockFunction<'a, K, B, V> = dyn 'a + Fn(&mut BlockMemoizer<K, B, V>, &B) -> Vec<V>;
+
+/// Memoization cache for a recursive user function that calculates a whole block of keys at
+/// once.
+///
+/// `block_of` maps each key to the block that holds it and its position in that block, and
+/// the user function calculates all of a block's values together.  The first lookup of any
+/// key in a block calculates and caches the whole block, and later lookups of keys in it just
+/// index into the cached block.  For a grid whose rows are calculated in one go, that costs
+/// one map entry per row instead of one per cell.
+///
+/// The user function can look up keys and blocks other than the one it is calculating, and
+/// dependencies between blocks must not be circular, even where the keys involved would not
+/// be.
+///
+/// ```
+/// use red_memo::BlockMemoizer;
+///
+/// // Binomial coefficients, calculated a row of Pascal's triangle at a time.
+/// fn row(mem: &mut BlockMemoizer<(usize, usize), usize, u64>, n: &usize) -> Vec<u64> {
+///     if *n == 0 {
+///         return vec![1];
+///     }
+///     let above = mem.lookup_block(&(n - 1));
+///     (0..=*n)
+///         .map(|k| if k == 0 || k == *n { 1 } else { above[k - 1] + above[k] })
+///         .collect()
+/// }
+///
+/// let mut mem = BlockMemoizer::new_hash(|&(n, k): &(usize, usize)| (n, k), row);
+/// assert_eq!(mem.lookup(&(40, 20)), 137846528820);
+/// assert_eq!(mem.lookup_immut(&(40, 3)), Some(9880));
+/// ```
+pub struct BlockMemoizer<'a, K: 'a, B: 'a, V: 'a> {
+    cache: Box<dyn 'a + MemoStruct<'a, B, MemoVal<Rc<[V]>>>>,
+    block_of: Rc<BlockOf<'a, K, B>>,
+    user_function: Rc<BlockFunction<'a, K, B, V>>,
+}
+
+impl<'a, K: 'a, B: 'a + Clone + Debug, V: 'a + Debug> Debug for BlockMemoizer<'a, K, B, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt_memoizer(f, "BlockMemoizer", &self.cache)
+    }
+}
+
+impl<'a, K: 'a + Debug, B: 'a + Clone + Debug, V: 'a + Clone + Debug> BlockMemoizer<'a, K, B, V> {
+    /// Creates a BlockMemoizer whose blocks are kept in a HashMap.
+    pub fn new_hash<L, F>(block_of: L, user: F) -> Self
+    where
+        B: Hash + Eq,
+        L: 'a + Fn(&K) -> (B, usize),
+        F: 'a + Fn(&mut BlockMemoizer<K, B, V>, &B) -> Vec<V>,
+    {
+        BlockMemoizer::with_cache(Box::new(HashMap::new()), Rc::new(block_of), Rc::new(user))
+    }
+    /// Creates a BlockMemoizer whose blocks are kept in a BTreeMap.
+    pub fn new_ord<L, F>(block_of: L, user: F) -> Self
+    where
+        B: Ord,
+        L: 'a + Fn(&K) -> (B, usize),
+        F: 'a + Fn(&mut BlockMemoizer<K, B, V>, &B) -> Vec<V>,
+    {
+        BlockMemoizer::with_cache(Box::new(BTreeMap::new()), Rc::new(block_of), Rc::new(user))
+    }
+    fn with_cache(
+        cache: Box<dyn 'a + MemoStruct<'a, B, MemoVal<Rc<[V]>>>>,
+        block_of: Rc<BlockOf<'a, K, B>>,
+        user_function: Rc<BlockFunction<'a, K, B, V>>,
+    ) -> Self {
+        BlockMemoizer {
+            cache,
+            block_of,
+            user_function,
+        }
+    }
+    /// Looks up a key in the cache, calculating its block if necessary.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if a circular dependency between blocks is detected, or if the
+    /// block is too short to hold `k`.
+    pub fn lookup(&mut self, k: &K) -> V {
+        let (b, i) = (self.block_of)(k);
+        let block = self.lookup_block(&b);
+        match block.get(i) {
+            Some(v) => v.clone(),
+            None => panic!(
+                "BlockMemoizer: key {:?} is at {} in block {:?}, which has {} values",
+                k,
+                i,
+                b,
+                block.len()
+            ),
+        }
+    }
+    /// Looks up a whole block in the cache, calculating it if necessary.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if a circular dependency between blocks is detected.
+    pub fn lookup_block(&mut self, b: &B) -> Rc<[V]> {
+        match self.cache.get(b) {
+            Some(MemoVal::Finished(block)) => return block,
+            Some(MemoVal::InProgress) => {
+                panic!("BlockMemoizer: circular dependency on block {:?}", b)
+            }
+            Some(MemoVal::Known) | None => {}
+        }
+        let _ = self.cache.insert(b.clone(), MemoVal::InProgress);
+        let user = Rc::clone(&self.user_function);
+        let values = guarded(
+            self,
+            |mem| {
+                mem.cache.remove(b);
+            },
+            |mem| (*user)(mem, b),
+        );
+        let block: Rc<[V]> = values.into();
+        let _ = self
+            .cache
+            .insert(b.clone(), MemoVal::Finished(Rc::clone(&block)));
+        block
+    }
+    /// Look up a key in the cache, but do not calculate it if its block is not present.
+    pub fn lookup_immut(&self, k: &K) -> Option<V> {
+        let (b, i) = (self.block_of)(k);
+        match self.cache.peek(&b) {
+            Some(MemoVal::Finished(block)) => block.get(i).cloned(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn one_calculation_per_block() {
+        let calls = Cell::new(0);
+        // The triangular numbers, in blocks of ten.
+        let mut mem = BlockMemoizer::new_ord(
+            |k: &u64| (k / 10, (k % 10) as usize),
+            |mem: &mut BlockMemoizer<u64, u64, u64>, b: &u64| {
+                calls.set(calls.get() + 1);
+                let mut sum = if *b == 0 {
+                    0
+                } else {
+                    mem.lookup(&(b * 10 - 1))
+                };
+                (b * 10..b * 10 + 10)
+                    .map(|k| {
+                        sum += k;
+                        sum
+                    })
+                    .collect()
+            },
+        );
+        assert_eq!(mem.lookup(&35), 630);
+        assert_eq!(calls.get(), 4);
+        assert_eq!(mem.lookup(&39), 780);
+        assert_eq!(mem.lookup_immut(&12), Some(78));
+        assert_eq!(mem.lookup_immut(&40), None);
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn panic_leaves_no_marker() {
+        let fail = Cell::new(true);
+        let mut mem = BlockMemoizer::new_hash(
+            |k: &u32| (k / 4, (k % 4) as usize),
+            |mem: &mut BlockMemoizer<u32, u32, u32>, b: &u32| {
+                if *b == 0 && fail.get() {
+                    panic!("block 0 unavailable");
+                }
+                let base = if *b == 0 { 0 } else { mem.lookup(&(b * 4 - 1)) };
+                (1..=4).map(|i| base + i).collect()
+            },
+        );
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mem.lookup(&9)));
+        assert!(result.is_err());
+        assert_eq!(mem.cache.iter().count(), 0);
+        fail.set(false);
+        assert_eq!(mem.lookup(&9), 10);
+    }
+}
@@ -0,0 +1,200 @@
+//! A memoizer for branch-and-bound recurrences, whose lookups carry an upper bound.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use crate::guard::{fmt_memoizer, guarded};
+use crate::MemoStruct;
+
+#[derive(Debug, Clone)]
+enum Bounded<V> {
+    InProgress,
+    Exact(V),
+    // The value is known to be greater than this bound, but was not calculated exactly.
+    Pruned(V),
+}
+
+type BoundedFunction<'a, K, V> = dyn 'a + Fn(&mut BoundedMemoizer<K, V>, &K, &V) -> Option<V>;
+
+/// Memoization cache for a recursive user function that prunes against an upper bound.
+///
+/// Each lookup passes a bound along with the key.  The user function returns `Some(v)` with
+/// the exact value if it is no greater than the bound, and `None` if it can tell the value
+/// exceeds the bound, typically without calculating it in full.  The cache remembers both
+/// outcomes: an exact value answers every later lookup of its key, and "greater than `b`"
+/// answers later lookups with a bound of `b` or less.  A lookup with a larger bound than any
+/// that has been pruned calculates the key again with the new bound.
+///
+/// The user function is responsible for threading bounds through the recursion.  When it
+/// looks up a subproblem, it passes the bound minus whatever the current step contributes, so
+/// the subproblem is pruned exactly when the whole would exceed the caller's bound.  Tighter
+/// bounds prune more but are reused less, since a pruned entry says nothing about larger
+/// bounds.
+///
+/// ```
+/// use red_memo::BoundedMemoizer;
+///
+/// // The fewest coins adding up to `n`, if that is at most `bound`.
+/// fn coins(mem: &mut BoundedMemoizer<u32, u32>, n: &u32, bound: &u32) -> Option<u32> {
+///     if *n == 0 {
+///         return Some(0);
+///     }
+///     if *bound == 0 {
+///         return None;
+///     }
+///     [1, 5, 12]
+///         .iter()
+///         .filter(|&&c| c <= *n)
+///         .filter_map(|c| mem.lookup(&(n - c), &(bound - 1)))
+///         .min()
+///         .map(|m| m + 1)
+/// }
+///
+/// let mut mem = BoundedMemoizer::new_hash(coins);
+/// assert_eq!(mem.lookup(&15, &2), None);
+/// assert_eq!(mem.lookup(&15, &10), Some(3));
+/// ```
+pub struct BoundedMemoizer<'a, K: 'a, V: 'a> {
+    cache: Box<dyn 'a + MemoStruct<'a, K, Bounded<V>>>,
+    user_function: Rc<BoundedFunction<'a, K, V>>,
+}
+
+impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> Debug for BoundedMemoizer<'a, K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt_memoizer(f, "BoundedMemoizer", &self.cache)
+    }
+}
+
+impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug + PartialOrd> BoundedMemoizer<'a, K, V> {
+    /// Creates a BoundedMemoizer based on HashMap.
+    pub fn new_hash<F>(user: F) -> Self
+    where
+        K: Hash + Eq,
+        F: 'a + Fn(&mut BoundedMemoizer<K, V>, &K, &V) -> Option<V>,
+    {
+        BoundedMemoizer::with_cache(Box::new(HashMap::new()), Rc::new(user))
+    }
+    /// Creates a BoundedMemoizer based on a BTreeMap.
+    pub fn new_ord<F>(user: F) -> Self
+    where
+        K: Ord,
+        F: 'a + Fn(&mut BoundedMemoizer<K, V>, &K, &V) -> Option<V>,
+    {
+        BoundedMemoizer::with_cache(Box::new(BTreeMap::new()), Rc::new(user))
+    }
+    fn with_cache(
+        cache: Box<dyn 'a + MemoStruct<'a, K, Bounded<V>>>,
+        user_function: Rc<BoundedFunction<'a, K, V>>,
+    ) -> Self {
+        BoundedMemoizer {
+            cache,
+            user_function,
+        }
+    }
+    /// Looks up a key, returning its value if it is no greater than `bound`.
+    ///
+    /// Returns `None` if the value is known to exceed `bound`, calculating the key with this
+    /// bound if the cache can't tell.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if a circular dependency is detected.
+    pub fn lookup(&mut self, k: &K, bound: &V) -> Option<V> {
+        let old = self.cache.get(k);
+        match &old {
+            Some(Bounded::InProgress) => {
+                panic!("BoundedMemoizer: circular dependency on key {:?}", k)
+            }
+            Some(Bounded::Exact(v)) => return Some(v.clone()).filter(|v| v <= bound),
+            Some(Bounded::Pruned(b)) if bound <= b => return None,
+            _ => {}
+        }
+        let _ = self.cache.insert(k.clone(), Bounded::InProgress);
+        let user = Rc::clone(&self.user_function);
+        // If the calculation panics, the entry goes back to what it was before.
+        let restore = |mem: &mut Self| match old {
+            Some(entry) => {
+                let _ = mem.cache.insert(k.clone(), entry);
+            }
+            None => {
+                mem.cache.remove(k);
+            }
+        };
+        match guarded(self, restore, |mem| (*user)(mem, k, bound)) {
+            Some(v) => {
+                let _ = self.cache.insert(k.clone(), Bounded::Exact(v.clone()));
+                Some(v).filter(|v| v <= bound)
+            }
+            None => {
+                let _ = self.cache.insert(k.clone(), Bounded::Pruned(bound.clone()));
+                None
+            }
+        }
+    }
+    /// Returns the exact value of a key if it has been calculated, without calculating it.
+    pub fn lookup_immut(&self, k: &K) -> Option<V> {
+        match self.cache.peek(k) {
+            Some(Bounded::Exact(v)) => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn pruned_entries_answer_smaller_bounds() {
+        let calls = Cell::new(0);
+        // The length of the shortest path from `n` down to 0, stepping by 1 or 3 but never
+        // onto a multiple of 4 other than 0.
+        let mut mem = BoundedMemoizer::new_ord(
+            |mem: &mut BoundedMemoizer<u32, u32>, n: &u32, bound: &u32| {
+                calls.set(calls.get() + 1);
+                if *n == 0 {
+                    return Some(0);
+                }
+                if *bound == 0 {
+                    return None;
+                }
+                [1, 3]
+                    .iter()
+                    .filter(|&&s| s <= *n && (n - s == 0 || (n - s) % 4 != 0))
+                    .filter_map(|s| mem.lookup(&(n - s), &(bound - 1)))
+                    .min()
+                    .map(|m| m + 1)
+            },
+        );
+        assert_eq!(mem.lookup(&10, &2), None);
+        let before = calls.get();
+        assert_eq!(mem.lookup(&10, &1), None);
+        assert_eq!(calls.get(), before);
+        assert_eq!(mem.lookup(&10, &10), Some(4));
+        assert_eq!(mem.lookup_immut(&10), Some(4));
+        assert_eq!(mem.lookup(&10, &3), None);
+        assert_eq!(mem.lookup(&10, &4), Some(4));
+    }
+
+    #[test]
+    fn panic_restores_pruned_entry() {
+        let broken = Cell::new(false);
+        let mut mem =
+            BoundedMemoizer::new_hash(|_: &mut BoundedMemoizer<u32, u32>, n: &u32, bound: &u32| {
+                if broken.get() {
+                    panic!("input unavailable");
+                }
+                Some(*n).filter(|n| n <= bound)
+            });
+        assert_eq!(mem.lookup(&5, &2), None);
+        broken.set(true);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mem.lookup(&5, &10)));
+        assert!(result.is_err());
+        assert_eq!(mem.lookup(&5, &1), None);
+        broken.set(false);
+        assert_eq!(mem.lookup(&5, &10), Some(5));
+    }
+}
@@ -0,0 +1,169 @@
+//! A memoizer for recursive user functions that can fail.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use crate::guard::{fmt_memoizer, guarded};
+use crate::{MemoStruct, MemoVal};
+
+type FallibleFunction<'a, K, V, E> =
+    dyn 'a + Fn(&mut FallibleMemoizer<K, V, E>, &K) -> Result<V, E>;
+
+/// Memoization cache for a recursive user function that returns a `Result`.
+///
+/// Only `Ok` values are cached.  When the user function returns `Err`, the error is passed
+/// back to the caller and nothing is cached for the key, so a later lookup calculates it
+/// again.  Errors from nested lookups are usually passed on with `?`, and every key whose
+/// calculation is abandoned that way is left uncached, while the keys that did finish before
+/// the error keep their values.  This suits errors that are transient, such as a failed read
+/// of an input, where a retry may succeed.
+///
+/// ```
+/// use red_memo::FallibleMemoizer;
+///
+/// // The number of ways to climb `n` stairs one or two at a time, if it fits in a u8.
+/// fn stairs(mem: &mut FallibleMemoizer<u32, u8, String>, n: &u32) -> Result<u8, String> {
+///     if *n < 2 {
+///         return Ok(1);
+///     }
+///     let a = mem.lookup(&(n - 1))?;
+///     let b = mem.lookup(&(n - 2))?;
+///     a.checked_add(b).ok_or_else(|| format!("overflow at {}", n))
+/// }
+///
+/// let mut mem = FallibleMemoizer::new_hash(stairs);
+/// assert_eq!(mem.lookup(&12), Ok(233));
+/// assert_eq!(mem.lookup(&13), Err("overflow at 13".to_string()));
+/// assert_eq!(mem.lookup_immut(&12), Some(233));
+/// ```
+pub struct FallibleMemoizer<'a, K: 'a, V: 'a, E: 'a> {
+    cache: Box<dyn 'a + MemoStruct<'a, K, MemoVal<V>>>,
+    user_function: Rc<FallibleFunction<'a, K, V, E>>,
+}
+
+impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug, E: 'a> Debug
+    for FallibleMemoizer<'a, K, V, E>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt_memoizer(f, "FallibleMemoizer", &self.cache)
+    }
+}
+
+impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug, E: 'a> FallibleMemoizer<'a, K, V, E> {
+    /// Creates a FallibleMemoizer based on HashMap.
+    pub fn new_hash<F>(user: F) -> Self
+    where
+        K: Hash + Eq,
+        F: 'a + Fn(&mut FallibleMemoizer<K, V, E>, &K) -> Result<V, E>,
+    {
+        FallibleMemoizer::with_cache(Box::new(HashMap::new()), Rc::new(user))
+    }
+    /// Creates a FallibleMemoizer based on a BTreeMap.
+    pub fn new_ord<F>(user: F) -> Self
+    where
+        K: Ord,
+        F: 'a + Fn(&mut FallibleMemoizer<K, V, E>, &K) -> Result<V, E>,
+    {
+        FallibleMemoizer::with_cache(Box::new(BTreeMap::new()), Rc::new(user))
+    }
+    fn with_cache(
+        cache: Box<dyn 'a + MemoStruct<'a, K, MemoVal<V>>>,
+        user_function: Rc<FallibleFunction<'a, K, V, E>>,
+    ) -> Self {
+        FallibleMemoizer {
+            cache,
+            user_function,
+        }
+    }
+    /// Looks up a key in the cache, calculating a value if necessary.
+    ///
+    /// Returns the error if the calculation fails, leaving no entry for `k` behind.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if a circular dependency is detected.
+    pub fn lookup(&mut self, k: &K) -> Result<V, E> {
+        match self.cache.get(k) {
+            Some(MemoVal::Finished(v)) => return Ok(v),
+            Some(MemoVal::InProgress) => {
+                panic!("FallibleMemoizer: circular dependency on key {:?}", k)
+            }
+            Some(MemoVal::Known) | None => {}
+        }
+        let _ = self.cache.insert(k.clone(), MemoVal::InProgress);
+        let user = Rc::clone(&self.user_function);
+        let result = guarded(
+            self,
+            |mem| {
+                mem.cache.remove(k);
+            },
+            |mem| (*user)(mem, k),
+        );
+        match result {
+            Ok(v) => {
+                let _ = self.cache.insert(k.clone(), MemoVal::Finished(v.clone()));
+                Ok(v)
+            }
+            Err(e) => {
+                self.cache.remove(k);
+                Err(e)
+            }
+        }
+    }
+    /// Look up a key in the cache, but do not calculate it if it is not present.
+    pub fn lookup_immut(&self, k: &K) -> Option<V> {
+        match self.cache.peek(k) {
+            Some(MemoVal::Finished(v)) => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn error_leaves_no_markers() {
+        let broken = Cell::new(true);
+        let calls = Cell::new(0);
+        let mut mem =
+            FallibleMemoizer::new_ord(|mem: &mut FallibleMemoizer<u32, u32, String>, n: &u32| {
+                calls.set(calls.get() + 1);
+                match *n {
+                    0 if broken.get() => Err("input unavailable".to_string()),
+                    0 => Ok(0),
+                    1 => Ok(1),
+                    n => Ok(mem.lookup(&(n - 1))? + mem.lookup(&(n - 2))?),
+                }
+            });
+        assert_eq!(mem.lookup(&3), Err("input unavailable".to_string()));
+        assert_eq!(mem.lookup_immut(&3), None);
+        assert_eq!(mem.lookup_immut(&2), None);
+        assert_eq!(mem.lookup_immut(&1), Some(1));
+        broken.set(false);
+        calls.set(0);
+        assert_eq!(mem.lookup(&3), Ok(2));
+        assert_eq!(calls.get(), 3);
+        assert_eq!(mem.lookup(&10), Ok(55));
+    }
+
+    #[test]
+    fn panic_leaves_no_marker() {
+        let broken = Cell::new(true);
+        let mut mem = FallibleMemoizer::new_hash(
+            |mem: &mut FallibleMemoizer<u32, u32, ()>, n: &u32| match *n {
+                0 if broken.get() => panic!("input unavailable"),
+                0 => Ok(0),
+                n => Ok(mem.lookup(&(n - 1))? + n),
+            },
+        );
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mem.lookup(&3)));
+        assert!(result.is_err());
+        broken.set(false);
+        assert_eq!(mem.lookup(&3), Ok(6));
+    }
+}
@@ -0,0 +1,36 @@
+//! A read-only snapshot of a Memoizer's finished entries.
+
+/// The finished entries of a Memoizer, frozen into a sorted vector.  See `Memoizer::freeze()`.
+///
+/// Lookups are binary searches that return references, so nothing is cloned and nothing is
+/// calculated.  There is no way to add entries or to look up keys that were not finished when
+/// the Memoizer was frozen.
+#[derive(Debug, Clone)]
+pub struct FrozenMemoizer<K, V> {
+    entries: Vec<(K, V)>,
+}
+
+impl<K: Ord, V> FrozenMemoizer<K, V> {
+    pub(crate) fn from_entries(mut entries: Vec<(K, V)>) -> Self {
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.shrink_to_fit();
+        FrozenMemoizer { entries }
+    }
+    /// Returns a reference to the value for `k`, if it was finished.
+    pub fn get(&self, k: &K) -> Option<&V> {
+        let i = self.entries.binary_search_by(|(ek, _)| ek.cmp(k)).ok()?;
+        Some(&self.entries[i].1)
+    }
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    /// Returns true if there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Iterates over the entries in ascending key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+}
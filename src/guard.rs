@@ -0,0 +1,45 @@
+//! Scaffolding shared by the memoizers that mark a key as in progress while calculating it.
+
+use std::fmt::{self, Debug};
+
+/// Runs `calculate` on `memo`, calling `undo` to take back the in-progress marker if the
+/// calculation unwinds.
+///
+/// The caller places the marker before calling this and replaces it with the result after.
+/// Nothing is caught: a drop guard lends `memo` to the calculation and cleans up as the panic
+/// passes through, so the happy path costs no more than a plain call.
+pub(crate) fn guarded<M, T>(
+    memo: &mut M,
+    undo: impl FnOnce(&mut M),
+    calculate: impl FnOnce(&mut M) -> T,
+) -> T {
+    let mut guard = Marker {
+        memo,
+        undo: Some(undo),
+    };
+    let t = calculate(&mut *guard.memo);
+    guard.undo = None;
+    t
+}
+
+struct Marker<'m, M, U: FnOnce(&mut M)> {
+    memo: &'m mut M,
+    undo: Option<U>,
+}
+
+impl<'m, M, U: FnOnce(&mut M)> Drop for Marker<'m, M, U> {
+    fn drop(&mut self) {
+        if let Some(undo) = self.undo.take() {
+            undo(self.memo);
+        }
+    }
+}
+
+/// Formats a memoizer as its cache alongside a user function that can't be printed.
+pub(crate) fn fmt_memoizer(f: &mut fmt::Formatter, name: &str, cache: &dyn Debug) -> fmt::Result {
+    write!(
+        f,
+        "{} {{ cache: {:?}, user_function: *unprintable* }}",
+        name, cache
+    )
+}
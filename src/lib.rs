@@ -1,4 +1,4 @@
-//! red_memo is a simple, safe, single-threaded, pure rust library for memoization and dynamic programming.
+//! red_memo is a simple, safe, pure rust library for memoization and dynamic programming.
 //!
 //! `Memoizer<K,V>` is the main cache type.  It can be initialized with an underlying
 //! `std::collections::HashMap` with `new_hash()`, or with an underlying
@@ -19,6 +19,10 @@
 //! If a value type cannot be made to implement Clone, or if it would be excessively costly to make
 //! copies, consider using `std::rc::Rc`.
 //!
+//! `Memoizer` is not `Send` or `Sync`.  For a cache shared between threads, or stored in a
+//! `static`, use `SyncMemoizer` instead.  To share one cache between several owners on a single
+//! thread, use `SharedMemoizer`.
+//!
 //! ```
 //!
 //! use red_memo::Memoizer;
@@ -44,28 +48,465 @@
 //!
 //! ```
 //!
+//! # Mutual recursion
+//!
+//! Two recurrences that call each other can't live in two separate Memoizers.  While one of
+//! them is calculating a value it is mutably borrowed, so the other can't reach back into it;
+//! wrapping them in `RefCell`s only turns that into an "already borrowed" panic at runtime.
+//! Instead, give one Memoizer a key type with a variant for each recurrence.  If the
+//! recurrences produce different value types, the value type can be an enum too.
+//!
+//! ```
+//! use red_memo::Memoizer;
+//!
+//! // Ways to tile a 3 x n board with dominoes (`Full`), and a 3 x n board with one corner
+//! // square already covered (`Partial`).
+//! #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+//! enum Board {
+//!     Full(u64),
+//!     Partial(u64),
+//! }
+//!
+//! fn tilings(mem: &mut Memoizer<Board, u64>, board: &Board) -> u64 {
+//!     match *board {
+//!         Board::Full(0) | Board::Partial(1) => 1,
+//!         Board::Full(1) | Board::Partial(0) => 0,
+//!         Board::Full(n) => {
+//!             mem.lookup(&Board::Full(n - 2)) + 2 * mem.lookup(&Board::Partial(n - 1))
+//!         }
+//!         Board::Partial(n) => {
+//!             mem.lookup(&Board::Full(n - 1)) + mem.lookup(&Board::Partial(n - 2))
+//!         }
+//!     }
+//! }
+//!
+//! let mut mem = Memoizer::new_hash(tilings);
+//! assert_eq!(mem.lookup(&Board::Full(8)), 153);
+//! ```
 //!
 
 #![deny(missing_docs)]
 
-use std::collections::{BTreeMap, HashMap};
-use std::fmt::Debug;
-use std::hash::Hash;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::fmt::{self, Debug, Display};
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+mod block;
+mod bounded;
+mod fallible;
+mod frozen;
+mod guard;
+mod packed;
+#[cfg(feature = "serde")]
+mod serde_key;
+mod shared;
+mod sync;
+
+pub use block::BlockMemoizer;
+pub use bounded::BoundedMemoizer;
+pub use fallible::FallibleMemoizer;
+pub use frozen::FrozenMemoizer;
+pub use packed::{DenseBounds, PackedMemoizer};
+pub use shared::SharedMemoizer;
+pub use sync::SyncMemoizer;
+
+/// Error returned when a circular dependency between keys is detected.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct CycleError<K> {
+    /// The key that closed the cycle.
+    pub key: K,
+}
+
+impl<K: Debug> fmt::Display for CycleError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "circular dependency on key {:?}", self.key)
+    }
+}
+
+impl<K: Debug> std::error::Error for CycleError<K> {}
+
+/// Error returned by `Memoizer::try_lookup()` when a calculation is abandoned.
+#[derive(Eq, PartialEq, Debug, Clone)]
+#[non_exhaustive]
+pub enum LookupError<K> {
+    /// A key was looked up while it was already being calculated.
+    Cycle(CycleError<K>),
+    /// The calculation of `key` made more than `max_fanout` nested lookups.
+    FanoutExceeded {
+        /// The key whose calculation was abandoned.
+        key: K,
+        /// The limit set with `Memoizer::set_max_fanout()`.
+        max_fanout: usize,
+    },
+}
+
+impl<K: Debug> fmt::Display for LookupError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LookupError::Cycle(e) => write!(f, "{}", e),
+            LookupError::FanoutExceeded { key, max_fanout } => write!(
+                f,
+                "calculation of key {:?} made more than {} lookups",
+                key, max_fanout
+            ),
+        }
+    }
+}
+
+impl<K: Debug> std::error::Error for LookupError<K> {}
+
+/// Counters describing how lookups were served.  See `Memoizer::stats()`.
+#[derive(Eq, PartialEq, Debug, Copy, Clone, Default)]
+pub struct CacheStats {
+    /// Lookups answered with a finished value from the cache.
+    pub hits: u64,
+    /// Lookups that called the user function.
+    pub misses: u64,
+    /// Misses whose value was not stored because the memoization predicate rejected the key.
+    pub predicate_skips: u64,
+    /// Finished values removed or dropped by `retain()`, `retain_top_k()`,
+    /// `clear_values_keep_keys()` or the eviction policy.
+    pub evictions: u64,
+    /// Misses on keys whose values had been dropped by `clear_values_keep_keys()` or evicted
+    /// by the eviction policy.  A steady rise while the cache is full means it is thrashing.
+    /// Keys removed outright, as by `invalidate()` or `retain()`, are forgotten, so calculating
+    /// them again is not counted here.
+    pub re_computations: u64,
+    /// Hits on lookups made from outside the user function.
+    pub top_level_hits: u64,
+    /// Misses on lookups made from outside the user function.
+    pub top_level_misses: u64,
+    /// Hits on lookups made by the user function while calculating another key.
+    pub recursive_hits: u64,
+    /// Misses on lookups made by the user function while calculating another key.
+    pub recursive_misses: u64,
+    /// Values calculated: one for every miss, plus the second calculations made by
+    /// `set_verify_determinism()`.
+    pub computations: u64,
+}
+
+impl CacheStats {
+    // The counts accumulated since `earlier` was taken.
+    fn since(&self, earlier: &CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits.saturating_sub(earlier.hits),
+            misses: self.misses.saturating_sub(earlier.misses),
+            predicate_skips: self.predicate_skips.saturating_sub(earlier.predicate_skips),
+            evictions: self.evictions.saturating_sub(earlier.evictions),
+            re_computations: self.re_computations.saturating_sub(earlier.re_computations),
+            top_level_hits: self.top_level_hits.saturating_sub(earlier.top_level_hits),
+            top_level_misses: self
+                .top_level_misses
+                .saturating_sub(earlier.top_level_misses),
+            recursive_hits: self.recursive_hits.saturating_sub(earlier.recursive_hits),
+            recursive_misses: self
+                .recursive_misses
+                .saturating_sub(earlier.recursive_misses),
+            computations: self.computations.saturating_sub(earlier.computations),
+        }
+    }
+}
+
+/// Error returned by `Memoizer::try_lookup_cancellable()` when the lookup was cancelled.
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "lookup cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Error returned by `Memoizer::lookup_with_fuel()` when the fuel ran out.
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub struct OutOfFuel;
+
+impl fmt::Display for OutOfFuel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "lookup ran out of fuel")
+    }
+}
+
+impl std::error::Error for OutOfFuel {}
+
+/// The differences between the finished entries of two Memoizers.  See `Memoizer::diff()`.
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct CacheDiff<K, V> {
+    /// Keys finished only in the Memoizer `diff()` was called on.
+    pub only_self: Vec<K>,
+    /// Keys finished only in the other Memoizer.
+    pub only_other: Vec<K>,
+    /// Keys finished in both with different values, with this Memoizer's value first.
+    pub differing: Vec<(K, V, V)>,
+}
+
+/// How much caching sped up a set of queries.  See `Memoizer::benchmark_caching()`.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct CacheBenefit {
+    /// The time taken without caching divided by the time taken with it.
+    pub speedup: f64,
+    /// Calls to the user function without caching, less the calls with it.
+    pub avoided_computations: usize,
+}
+
+/// A source of the current time for a Memoizer.  See `Memoizer::set_clock()`.
+///
+/// Any `Fn() -> Instant` closure is a clock, so a test can step time forward by hand instead of
+/// sleeping.
+pub trait Clock {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+}
+
+impl<F: Fn() -> Instant> Clock for F {
+    fn now(&self) -> Instant {
+        self()
+    }
+}
+
+/// The clock a Memoizer starts out with, which reads `Instant::now()`.
+#[derive(Eq, PartialEq, Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// How a Memoizer bounds the finished entries it keeps.  See `Memoizer::builder()` and
+/// `Memoizer::set_eviction_policy()`.
+///
+/// Whichever policy is in effect, in-progress and pinned entries are never evicted.
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum EvictionPolicy {
+    /// Keep every finished entry.  This is the default.
+    None,
+    /// Keep at most this many finished entries, evicting the least recently used, as
+    /// `Memoizer::set_capacity()` does.
+    Lru(usize),
+    /// Keep at most this many finished entries, evicting the least frequently used.  Ties go
+    /// to the least recently used.
+    Lfu(usize),
+    /// Keep at most this many finished entries, evicting the one finished longest ago.
+    /// Lookups don't refresh an entry, so every entry gets the same time in the cache.
+    Fifo(usize),
+    /// Keep the total size of the finished values at most this many bytes, evicting the least
+    /// recently used.  Sizes are measured by the size function, which this policy requires.
+    ByteBounded(usize),
+}
+
+/// Error returned when an eviction policy is configured inconsistently.  See
+/// `MemoizerBuilder::build_hash()` and `Memoizer::set_eviction_policy()`.
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum PolicyError {
+    /// `EvictionPolicy::ByteBounded` was chosen without a size function to measure values.
+    MissingSizeFunction,
+    /// A size function was given along with a policy that doesn't measure values.
+    UnusedSizeFunction,
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyError::MissingSizeFunction => {
+                write!(f, "byte-bounded eviction needs a size function")
+            }
+            PolicyError::UnusedSizeFunction => {
+                write!(f, "size function given for a policy that doesn't use one")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+/// A reusable buffer for `Memoizer::new_hash_with_scratch()`.
+pub trait Scratch: Default {
+    /// Empties the buffer, keeping its allocated capacity.
+    fn clear(&mut self);
+}
+
+impl<T> Scratch for Vec<T> {
+    fn clear(&mut self) {
+        Vec::clear(self)
+    }
+}
+
+impl Scratch for String {
+    fn clear(&mut self) {
+        String::clear(self)
+    }
+}
+
+impl<K: Hash + Eq, V> Scratch for HashMap<K, V> {
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+}
+
+impl<T: Hash + Eq> Scratch for HashSet<T> {
+    fn clear(&mut self) {
+        HashSet::clear(self)
+    }
+}
+
+/// A point in a Memoizer's history of finished entries.  See `Memoizer::mark()`.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Copy, Clone)]
+pub struct Marker(u64);
+
+/// Marks a lifetime as captured by a returned `impl Trait`, such as `Memoizer::as_fn_mut()`.
+///
+/// Every type implements it, so it places no constraint on callers.
+#[doc(hidden)]
+pub trait Captures<'x> {}
+
+impl<'x, T: ?Sized> Captures<'x> for T {}
+
+// Unwinding payload used to abandon a calculation.  The reason is left in `Memoizer::abort`,
+// since the payload must be `'static` and keys need not be.
+struct Abort;
+
+// Why a calculation was abandoned.
+enum Abandon<K> {
+    Error(LookupError<K>),
+    Cancelled,
+    OutOfFuel,
+    // A panic that did not come from the Memoizer, which is passed on untouched.
+    Panic(Box<dyn Any + Send>),
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+enum Visit {
+    Unseen,
+    Open,
+    Done,
+}
 
 #[derive(Eq, Ord, PartialOrd, PartialEq, Debug, Copy, Clone)]
 enum MemoVal<V> {
     InProgress,
     Finished(V),
+    // A value was finished for the key and then dropped by `clear_values_keep_keys()`.  The
+    // key still counts as known, but a lookup calculates it again as if it were missing.
+    Known,
+}
+
+#[derive(Debug, Clone)]
+struct MemoEntry<K, V> {
+    val: MemoVal<V>,
+    pinned: bool,
+    deps: Vec<K>,
+    // The sequence number assigned when the value was finished.
+    seq: u64,
+    // Where the entry stands in `Memoizer::uses`, or `UNRANKED` if it isn't there.
+    used: Rank,
+}
+
+impl<K, V> MemoEntry<K, V> {
+    fn new(val: MemoVal<V>) -> Self {
+        MemoEntry {
+            val,
+            pinned: false,
+            deps: Vec::new(),
+            seq: 0,
+            used: UNRANKED,
+        }
+    }
+}
+
+// An entry's place in eviction order: its use count under `EvictionPolicy::Lfu` and 0 under the
+// other policies, then the tick of its last use, or of its insertion under `Fifo`.
+type Rank = (u64, u64);
+
+// The rank of an entry that no policy is tracking.  Ticks start at 1, so no tracked entry has it.
+const UNRANKED: Rank = (0, 0);
+
+// The finished entries an eviction policy is tracking, first to be evicted first, with the size
+// of each value and their total, for `EvictionPolicy::ByteBounded`.
+struct Uses<K> {
+    ranks: BTreeMap<Rank, (K, usize)>,
+    bytes: usize,
+}
+
+impl<K> Uses<K> {
+    fn new() -> Self {
+        Uses {
+            ranks: BTreeMap::new(),
+            bytes: 0,
+        }
+    }
+    fn insert(&mut self, rank: Rank, k: K, size: usize) {
+        self.bytes += size;
+        self.ranks.insert(rank, (k, size));
+    }
+    fn remove(&mut self, rank: &Rank) -> Option<(K, usize)> {
+        let removed = self.ranks.remove(rank)?;
+        self.bytes -= removed.1;
+        Some(removed)
+    }
+    fn len(&self) -> usize {
+        self.ranks.len()
+    }
+    fn clear(&mut self) {
+        self.ranks.clear();
+        self.bytes = 0;
+    }
+}
+
+// A calculation in progress.  `saved` is true if an in-progress marker was placed for `key` in
+// the cache, and false if `key` was recorded in `Memoizer::in_flight` instead.  `known` is true
+// if the in-progress marker replaced a `MemoVal::Known` entry.  `seq` is `Memoizer::seq` when
+// the calculation started, so a value stored for `key` since then can be told apart.
+#[derive(Debug)]
+struct Frame<K> {
+    key: K,
+    saved: bool,
+    known: bool,
+    seq: u64,
+    lookups: usize,
+    deps: Vec<K>,
 }
 
 trait MemoStruct<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug>: Debug {
     fn insert(&mut self, k: K, v: V) -> Result<(), V>;
     fn get(&self, k: &K) -> Option<V>;
+    fn peek(&self, k: &K) -> Option<&V>;
     // TODO: remove get_mut?
     fn get_mut(&mut self, k: &K) -> Option<&mut V>;
-    // TODO: Add iter() and into_iter() implementations somehow.
-    // TODO: Make it possible to manually initialize the cache.  public `store()`?
+    fn remove(&mut self, k: &K) -> Option<V>;
+    fn iter<'s>(&'s self) -> Box<dyn 's + Iterator<Item = (&'s K, &'s V)>>;
+    fn values_mut<'s>(&'s mut self) -> Box<dyn 's + Iterator<Item = &'s mut V>>;
+    fn new_index(&self) -> Box<dyn 'a + MemoStruct<'a, K, usize>>;
+    fn empty(&self) -> Box<dyn 'a + MemoStruct<'a, K, V>>;
+    fn into_entries(self: Box<Self>) -> Box<dyn 'a + Iterator<Item = (K, V)>>;
+    // Like `peek()`, for the lookups the Memoizer serves, which are the ones counted by
+    // `collisions()`.
+    fn probe(&self, k: &K) -> Option<&V> {
+        self.peek(k)
+    }
+    // Turns on comparing keys for backends that don't compare them already.
+    #[cfg(feature = "serde")]
+    fn set_verify_keys(&mut self, _verify: bool) {}
+    // The number of probes whose key's hash was shared by another key's entry.
+    #[cfg(feature = "serde")]
+    fn collisions(&self) -> u64 {
+        0
+    }
 }
 
 impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> MemoStruct<'a, K, V> for HashMap<K, V>
@@ -86,11 +527,32 @@ where
         }
     }
     fn get(&self, k: &K) -> Option<V> {
-        self.get(k).map(|vref| vref.clone())
+        HashMap::get(self, k).cloned()
+    }
+    fn peek(&self, k: &K) -> Option<&V> {
+        HashMap::get(self, k)
     }
     fn get_mut(&mut self, k: &K) -> Option<&mut V> {
         HashMap::get_mut(self, k)
     }
+    fn remove(&mut self, k: &K) -> Option<V> {
+        HashMap::remove(self, k)
+    }
+    fn iter<'s>(&'s self) -> Box<dyn 's + Iterator<Item = (&'s K, &'s V)>> {
+        Box::new(HashMap::iter(self))
+    }
+    fn values_mut<'s>(&'s mut self) -> Box<dyn 's + Iterator<Item = &'s mut V>> {
+        Box::new(HashMap::values_mut(self))
+    }
+    fn new_index(&self) -> Box<dyn 'a + MemoStruct<'a, K, usize>> {
+        Box::new(HashMap::new())
+    }
+    fn empty(&self) -> Box<dyn 'a + MemoStruct<'a, K, V>> {
+        Box::new(HashMap::new())
+    }
+    fn into_entries(self: Box<Self>) -> Box<dyn 'a + Iterator<Item = (K, V)>> {
+        Box::new(HashMap::into_iter(*self))
+    }
 }
 
 impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> MemoStruct<'a, K, V> for BTreeMap<K, V>
@@ -111,18 +573,133 @@ where
         }
     }
     fn get(&self, k: &K) -> Option<V> {
-        BTreeMap::get(self, k).map(|vref| vref.clone())
+        BTreeMap::get(self, k).cloned()
+    }
+    fn peek(&self, k: &K) -> Option<&V> {
+        BTreeMap::get(self, k)
     }
     fn get_mut(&mut self, k: &K) -> Option<&mut V> {
         BTreeMap::get_mut(self, k)
     }
+    fn remove(&mut self, k: &K) -> Option<V> {
+        BTreeMap::remove(self, k)
+    }
+    fn iter<'s>(&'s self) -> Box<dyn 's + Iterator<Item = (&'s K, &'s V)>> {
+        Box::new(BTreeMap::iter(self))
+    }
+    fn values_mut<'s>(&'s mut self) -> Box<dyn 's + Iterator<Item = &'s mut V>> {
+        Box::new(BTreeMap::values_mut(self))
+    }
+    fn new_index(&self) -> Box<dyn 'a + MemoStruct<'a, K, usize>> {
+        Box::new(BTreeMap::new())
+    }
+    fn empty(&self) -> Box<dyn 'a + MemoStruct<'a, K, V>> {
+        Box::new(BTreeMap::new())
+    }
+    fn into_entries(self: Box<Self>) -> Box<dyn 'a + Iterator<Item = (K, V)>> {
+        Box::new(BTreeMap::into_iter(*self))
+    }
+}
+
+// Assigns dense indices to keys, using the same kind of map as the cache it came from.
+struct KeyIndex<'a, K: 'a + Clone + Debug> {
+    map: Box<dyn 'a + MemoStruct<'a, K, usize>>,
+    keys: Vec<K>,
+}
+
+impl<'a, K: 'a + Clone + Debug> KeyIndex<'a, K> {
+    fn new(map: Box<dyn 'a + MemoStruct<'a, K, usize>>) -> Self {
+        let keys = Vec::new();
+        KeyIndex { map, keys }
+    }
+    fn index(&mut self, k: &K) -> usize {
+        self.map.get(k).unwrap_or_else(|| {
+            let i = self.keys.len();
+            let _ = self.map.insert(k.clone(), i);
+            self.keys.push(k.clone());
+            i
+        })
+    }
+    fn get(&self, k: &K) -> Option<usize> {
+        self.map.get(k)
+    }
+}
+
+type UserFunction<'a, K, V> = dyn 'a + Fn(&mut Memoizer<K, V>, &K) -> V;
+type MemoPredicate<'a, K> = dyn 'a + Fn(&K) -> bool;
+type DependencyOrder<'a, K> = dyn 'a + Fn(&K, &K) -> std::cmp::Ordering;
+type ValueEq<'a, V> = dyn 'a + Fn(&V, &V) -> bool;
+type SizeFunction<'a, V> = dyn 'a + Fn(&V) -> usize;
+
+// A slower cache consulted on misses and written after calculations.  See
+// `Memoizer::set_read_through()`.
+struct ReadThrough<'a, K, V> {
+    read: Box<ReadFunction<'a, K, V>>,
+    write: Box<WriteFunction<'a, K, V>>,
+    // Values waiting to be written, with `Memoizer::set_writeback_batch()`.
+    pending: RefCell<Vec<(K, V)>>,
+}
+
+impl<'a, K, V> ReadThrough<'a, K, V> {
+    fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.borrow_mut());
+        for (k, v) in pending {
+            (self.write)(&k, &v);
+        }
+    }
 }
 
+impl<'a, K, V> Drop for ReadThrough<'a, K, V> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+type ReadFunction<'a, K, V> = dyn 'a + Fn(&K) -> Option<V>;
+type WriteFunction<'a, K, V> = dyn 'a + Fn(&K, &V);
+
 /// Memoization cache for a recursive user function
 pub struct Memoizer<'a, K: 'a, V: 'a + Clone + Debug> {
-    cache: Box<dyn 'a + MemoStruct<'a, K, MemoVal<V>>>,
-    user_function: Rc<dyn 'a + Fn(&mut Memoizer<K, V>, &K) -> V>,
-    memo_predicate: Option<Box<dyn 'a + Fn(&K) -> bool>>,
+    cache: Box<dyn 'a + MemoStruct<'a, K, MemoEntry<K, V>>>,
+    user_function: Rc<UserFunction<'a, K, V>>,
+    memo_predicate: Option<Rc<MemoPredicate<'a, K>>>,
+    dependency_order: Option<Rc<DependencyOrder<'a, K>>>,
+    read_through: Option<Rc<ReadThrough<'a, K, V>>>,
+    track_dependencies: bool,
+    max_fanout: Option<usize>,
+    caching_enabled: bool,
+    // Keys being calculated without an in-progress marker in the cache, for cycle detection.
+    in_flight: Box<dyn 'a + MemoStruct<'a, K, usize>>,
+    stack: Vec<Frame<K>>,
+    stats: CacheStats,
+    // The deepest the stack of calculations has been.
+    max_depth: usize,
+    // The number of values finished so far, used to number them.
+    seq: u64,
+    // The number of finished entries.
+    n_finished: usize,
+    // The flag of the innermost `try_lookup_cancellable()` call under way.
+    cancel: Option<&'a AtomicBool>,
+    // The number of fresh calculations left before `lookup_with_fuel()` gives up.
+    fuel: Option<usize>,
+    // The entries calculated and cached during the innermost `lookup_and_collect_new()` call.
+    collected: Option<Vec<(K, V)>>,
+    abort: Option<Abandon<K>>,
+    policy: EvictionPolicy,
+    // Measures values for `EvictionPolicy::ByteBounded`.
+    size_function: Option<Rc<SizeFunction<'a, V>>>,
+    // Finished entries in eviction order.  Only kept while there is a policy; under
+    // `EvictionPolicy::None` it stays empty, every entry is `UNRANKED`, and no key is cloned
+    // into it.
+    uses: Uses<K>,
+    tick: u64,
+    // Where capacity evictions go, and where misses look before calculating.
+    overflow: Option<Box<Memoizer<'a, K, V>>>,
+    // Compares a value with its recalculation, if `set_verify_determinism(true)` is in effect,
+    // along with the hasher keys that pick which values are recalculated.
+    verify_determinism: Option<(Rc<ValueEq<'a, V>>, RandomState)>,
+    writeback_batch: Option<usize>,
+    clock: Rc<dyn 'a + Clock>,
 }
 
 impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> Debug for Memoizer<'a, K, V> {
@@ -140,6 +717,115 @@ impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> Debug for Memoizer<'a, K,
     }
 }
 
+/// Configures a Memoizer's eviction policy before it is built.
+///
+/// The builder checks that the policy and the size function fit together, which the separate
+/// setters can't do as they are called one at a time:
+///
+/// ```
+/// use red_memo::{EvictionPolicy, Memoizer, MemoizerBuilder, PolicyError};
+///
+/// let user = |_: &mut Memoizer<usize, Vec<u8>>, k: &usize| vec![0; *k];
+/// let mut mem = MemoizerBuilder::new()
+///     .eviction_policy(EvictionPolicy::ByteBounded(100))
+///     .size_function(|v: &Vec<u8>| v.len())
+///     .build_hash(user)
+///     .unwrap();
+/// mem.lookup(&60);
+/// mem.lookup(&50);
+/// assert_eq!(mem.lookup_immut(&60), None);
+///
+/// let unmeasured = MemoizerBuilder::new()
+///     .eviction_policy(EvictionPolicy::ByteBounded(100))
+///     .build_hash(user);
+/// assert_eq!(unmeasured.unwrap_err(), PolicyError::MissingSizeFunction);
+/// ```
+pub struct MemoizerBuilder<'a, V> {
+    policy: EvictionPolicy,
+    size_function: Option<Rc<SizeFunction<'a, V>>>,
+}
+
+impl<'a, V> Debug for MemoizerBuilder<'a, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let size_str = self
+            .size_function
+            .as_ref()
+            .map(|_| "*present*")
+            .unwrap_or("*not present*");
+        write!(
+            f,
+            "MemoizerBuilder {{ policy: {:?}, size_function: {} }}",
+            self.policy, size_str
+        )
+    }
+}
+
+impl<'a, V: 'a + Clone + Debug> Default for MemoizerBuilder<'a, V> {
+    fn default() -> Self {
+        MemoizerBuilder::new()
+    }
+}
+
+impl<'a, V: 'a + Clone + Debug> MemoizerBuilder<'a, V> {
+    /// Creates a builder for a Memoizer that keeps every entry.
+    pub fn new() -> Self {
+        MemoizerBuilder {
+            policy: EvictionPolicy::None,
+            size_function: None,
+        }
+    }
+    /// Chooses how the cache is bounded.  See `Memoizer::set_eviction_policy()`.
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+    /// Sets the function that measures values, which `EvictionPolicy::ByteBounded` requires
+    /// and the other policies don't accept.  See `Memoizer::set_size_function()`.
+    pub fn size_function<S>(mut self, size: S) -> Self
+    where
+        S: 'a + Fn(&V) -> usize,
+    {
+        self.size_function = Some(Rc::new(size));
+        self
+    }
+    /// Builds a Memoizer based on HashMap, as `Memoizer::new_hash()` does.
+    ///
+    /// Returns an error if the policy needs a size function and none was given, or if one was
+    /// given that the policy would never call.
+    pub fn build_hash<K, F>(self, user: F) -> Result<Memoizer<'a, K, V>, PolicyError>
+    where
+        K: 'a + Clone + Debug + Hash + Eq,
+        F: 'a + Fn(&mut Memoizer<K, V>, &K) -> V,
+    {
+        self.validate()?;
+        Ok(self.configure(Memoizer::new_hash(user)))
+    }
+    /// Builds a Memoizer based on a BTreeMap, as `Memoizer::new_ord()` does.
+    ///
+    /// See `build_hash()` for the errors.
+    pub fn build_ord<K, F>(self, user: F) -> Result<Memoizer<'a, K, V>, PolicyError>
+    where
+        K: 'a + Clone + Debug + Ord,
+        F: 'a + Fn(&mut Memoizer<K, V>, &K) -> V,
+    {
+        self.validate()?;
+        Ok(self.configure(Memoizer::new_ord(user)))
+    }
+    fn validate(&self) -> Result<(), PolicyError> {
+        match (self.policy, &self.size_function) {
+            (EvictionPolicy::ByteBounded(_), None) => Err(PolicyError::MissingSizeFunction),
+            (EvictionPolicy::ByteBounded(_), Some(_)) | (_, None) => Ok(()),
+            (_, Some(_)) => Err(PolicyError::UnusedSizeFunction),
+        }
+    }
+    // Applies the validated settings to a new, empty Memoizer.
+    fn configure<K: 'a + Clone + Debug>(self, mut memo: Memoizer<'a, K, V>) -> Memoizer<'a, K, V> {
+        memo.size_function = self.size_function;
+        memo.policy = self.policy;
+        memo
+    }
+}
+
 impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> Memoizer<'a, K, V> {
     /// Creates a Memoizer based on HashMap.
     pub fn new_hash<F>(user: F) -> Self
@@ -149,12 +835,7 @@ impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> Memoizer<'a, K, V> {
     {
         let cache = Box::new(HashMap::new());
         let user_function = Rc::new(user);
-        let memo_predicate = None;
-        Memoizer {
-            cache,
-            user_function,
-            memo_predicate,
-        }
+        Memoizer::with_cache(cache, user_function)
     }
     /// Creates a Memoizer based on a BTreeMap.
     pub fn new_ord<F>(user: F) -> Self
@@ -164,13 +845,153 @@ impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> Memoizer<'a, K, V> {
     {
         let cache = Box::new(BTreeMap::new());
         let user_function = Rc::new(user);
+        Memoizer::with_cache(cache, user_function)
+    }
+    /// Creates a Memoizer based on HashMap, with its cache filled from `entries`.
+    ///
+    /// This is the counterpart of `export()` and of the `Serialize` implementation: the user
+    /// function can't be saved along with the values, so it is attached again here.
+    pub fn from_entries_hash<F, I>(user: F, entries: I) -> Self
+    where
+        K: Hash + Eq,
+        F: 'a + Fn(&mut Memoizer<K, V>, &K) -> V,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut memo = Memoizer::new_hash(user);
+        memo.import(entries);
+        memo
+    }
+    /// Creates a Memoizer based on a BTreeMap, with its cache filled from `entries`.
+    ///
+    /// See `from_entries_hash()`.
+    pub fn from_entries_ord<F, I>(user: F, entries: I) -> Self
+    where
+        K: Ord,
+        F: 'a + Fn(&mut Memoizer<K, V>, &K) -> V,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut memo = Memoizer::new_ord(user);
+        memo.import(entries);
+        memo
+    }
+    /// Creates a Memoizer based on HashMap whose user function borrows a reusable scratch
+    /// buffer.
+    ///
+    /// Each calculation is handed a buffer that is cleared but keeps its allocation from earlier
+    /// calculations.  A calculation that looks up other keys gets a different buffer for each
+    /// level of nesting, so a buffer is never shared by two calculations that are running at
+    /// the same time.  The Memoizer ends up holding as many buffers as the deepest chain of
+    /// nested calculations.
+    pub fn new_hash_with_scratch<S, F>(user: F) -> Self
+    where
+        K: Hash + Eq,
+        S: 'a + Scratch,
+        F: 'a + Fn(&mut Memoizer<K, V>, &K, &mut S) -> V,
+    {
+        let pool: RefCell<Vec<S>> = RefCell::new(Vec::new());
+        Memoizer::new_hash(move |mem: &mut Memoizer<K, V>, k: &K| {
+            let mut scratch = pool.borrow_mut().pop().unwrap_or_default();
+            scratch.clear();
+            let v = user(mem, k, &mut scratch);
+            pool.borrow_mut().push(scratch);
+            v
+        })
+    }
+    /// Creates a Memoizer based on HashMap from a recurrence given as two separate functions:
+    /// `subkeys` lists the keys a key depends on, and `combine` calculates its value from the
+    /// values of those keys, in the same order.
+    ///
+    /// All of a key's subkeys are evaluated before `combine` is called, so there is no
+    /// short-circuiting; a recurrence that only sometimes needs a subkey is better written as
+    /// an ordinary user function.  The subkeys are evaluated bottom-up the way
+    /// `lookup_iterative()` does it, so long chains of dependencies don't overflow the stack.
+    ///
+    /// ```
+    /// use red_memo::Memoizer;
+    ///
+    /// let subkeys = |n: &u64| if *n < 2 { vec![] } else { vec![n - 1, n - 2] };
+    /// let combine = |n: &u64, vs: &[u64]| if *n < 2 { *n } else { vs[0] + vs[1] };
+    /// let mut mem = Memoizer::new_hash_structured(subkeys, combine);
+    /// assert_eq!(mem.lookup(&90), 2880067194370816120);
+    /// ```
+    pub fn new_hash_structured<S, C>(subkeys: S, combine: C) -> Self
+    where
+        K: Hash + Eq,
+        S: 'a + Fn(&K) -> Vec<K>,
+        C: 'a + Fn(&K, &[V]) -> V,
+    {
+        Memoizer::new_hash(Memoizer::structured(subkeys, combine))
+    }
+    /// Creates a Memoizer based on a BTreeMap from a recurrence given as separate `subkeys`
+    /// and `combine` functions.  See `new_hash_structured()`.
+    pub fn new_ord_structured<S, C>(subkeys: S, combine: C) -> Self
+    where
+        K: Ord,
+        S: 'a + Fn(&K) -> Vec<K>,
+        C: 'a + Fn(&K, &[V]) -> V,
+    {
+        Memoizer::new_ord(Memoizer::structured(subkeys, combine))
+    }
+    fn structured<S, C>(subkeys: S, combine: C) -> impl 'a + Fn(&mut Memoizer<K, V>, &K) -> V
+    where
+        S: 'a + Fn(&K) -> Vec<K>,
+        C: 'a + Fn(&K, &[V]) -> V,
+    {
+        move |mem: &mut Memoizer<K, V>, k: &K| {
+            let vs: Vec<V> = subkeys(k)
+                .iter()
+                .map(|s| mem.lookup_iterative(s, &subkeys, &combine))
+                .collect();
+            combine(k, &vs)
+        }
+    }
+    fn with_cache(
+        cache: Box<dyn 'a + MemoStruct<'a, K, MemoEntry<K, V>>>,
+        user_function: Rc<UserFunction<'a, K, V>>,
+    ) -> Self {
         let memo_predicate = None;
+        let in_flight = cache.new_index();
         Memoizer {
             cache,
             user_function,
             memo_predicate,
+            dependency_order: None,
+            read_through: None,
+            track_dependencies: false,
+            max_fanout: None,
+            caching_enabled: true,
+            in_flight,
+            stack: Vec::new(),
+            stats: CacheStats::default(),
+            max_depth: 0,
+            seq: 0,
+            n_finished: 0,
+            cancel: None,
+            fuel: None,
+            collected: None,
+            abort: None,
+            policy: EvictionPolicy::None,
+            size_function: None,
+            uses: Uses::new(),
+            tick: 0,
+            overflow: None,
+            verify_determinism: None,
+            writeback_batch: None,
+            clock: Rc::new(SystemClock),
         }
     }
+    // Creates an empty Memoizer with the same backend, user function and settings as this one.
+    // The read-through store is not shared, so a sibling really does start out cold.
+    fn sibling(&self) -> Self {
+        let mut memo = Memoizer::with_cache(self.cache.empty(), Rc::clone(&self.user_function));
+        memo.memo_predicate = self.memo_predicate.clone();
+        memo.dependency_order = self.dependency_order.clone();
+        memo.track_dependencies = self.track_dependencies;
+        memo.max_fanout = self.max_fanout;
+        memo.caching_enabled = self.caching_enabled;
+        memo.clock = Rc::clone(&self.clock);
+        memo
+    }
     /// Sets a memoization predicate for the Memoizer.
     ///
     /// When a `Memoizer` has a memoization predicate set, keys not matched by the predicate will
@@ -181,95 +1002,3718 @@ impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> Memoizer<'a, K, V> {
     ///
     /// If there is a class of keys for which directly computing their value takes the same effort
     /// as lookinng up a key and cloning a value, it makes sense to use a predicate to keep those
-    /// keys out of the cache.
-    ///
-    /// TODO: add this to public api along with tests, or get rid of it.
-    #[allow(dead_code)]
-    fn set_memo_predicate<P>(&mut self, predicate: P)
+    /// keys out of the cache.  Keys rejected by the predicate are calculated again on every
+    /// lookup, and each such calculation is counted in `stats().predicate_skips`.
+    pub fn set_memo_predicate<P>(&mut self, predicate: P)
     where
         P: 'a + Fn(&K) -> bool,
     {
-        self.memo_predicate = Some(Box::new(predicate));
+        self.memo_predicate = Some(Rc::new(predicate));
     }
-    /// Looks up a key in the cache, calculating a value if necessary.
+    /// Declares an order in which every key's dependencies come before the key itself.
     ///
-    /// # Panics
+    /// The order is used by `lookup_ordered()`.  For a recurrence where each key only depends
+    /// on smaller keys, it is just the keys' natural order.
+    pub fn set_dependency_order<C>(&mut self, order: C)
+    where
+        C: 'a + Fn(&K, &K) -> std::cmp::Ordering,
+    {
+        self.dependency_order = Some(Rc::new(order));
+    }
+    /// Puts a slower, persistent cache behind this one, reached through a pair of closures.
     ///
-    /// This method will panic if a circular dependency is detected.
+    /// On a miss, `read` is called before the user function.  If it returns a value, that value
+    /// is stored in memory and returned without calculating anything.  Otherwise the value is
+    /// calculated as usual, and once it has been stored in memory it is passed to `write`.
+    /// Keys rejected by the memoization predicate are neither stored in memory nor written,
+    /// and neither closure is called while caching is turned off.  Values that `read` supplies
+    /// do not count as hits or misses in `stats()`.
     ///
-    /// Before the Memoizer starts calculating a value for a particular key, it places an
-    /// "in-progress" marker in the cache for that key.  After that key's value is caculated, the
-    /// "in-progress" marker is replaced with the finished value.  If an in-progress key is passed
-    /// to `lookup()`, this indicates a circular dependency.
+    /// This is a quick way to wire up something like an on-disk key-value store, with the
+    /// closures doing any serialization that it needs.
+    pub fn set_read_through<R, W>(&mut self, read: R, write: W)
+    where
+        R: 'a + Fn(&K) -> Option<V>,
+        W: 'a + Fn(&K, &V),
+    {
+        self.read_through = Some(Rc::new(ReadThrough {
+            read: Box::new(read),
+            write: Box::new(write),
+            pending: RefCell::new(Vec::new()),
+        }));
+    }
+    /// Buffers the writes to the read-through store, passing them to `write` in batches of
+    /// `n` instead of one at a time.
     ///
-    pub fn lookup(&mut self, k: &K) -> V {
-        let cachev = self.cache.get(k).unwrap_or_else(|| {
-            let save = self.memo_predicate.as_ref().map(|p| p(k)).unwrap_or(true);
-            if save {
-                self.cache
-                    .insert(k.clone(), MemoVal::InProgress)
-                    .unwrap_or_else(|_| {
-                        panic!("Did not expect to see a memo cacne entry for key {:?}", k)
-                    });
-            }
-            let user = Rc::clone(&self.user_function);
-            let v = (*user)(self, k);
-            if save {
-                self.cache
-                    .get_mut(k)
-                    .map(|vr| *vr = MemoVal::Finished(v.clone()));
-            }
-            MemoVal::Finished(v)
-        });
-        match cachev {
-            MemoVal::InProgress => panic!("Memoizer: circular dependency on key {:?}", k),
-            MemoVal::Finished(v) => v,
+    /// Calculated values are cached in memory straight away as usual, and pile up until `n` of
+    /// them are waiting or `flush()` is called.  Whatever is still waiting is written when the
+    /// Memoizer is dropped, or when `set_read_through()` replaces the store.  A value that is
+    /// evicted from memory before it has been written is calculated again if it is looked up,
+    /// since `read` can't find it yet.
+    pub fn set_writeback_batch(&mut self, n: usize) {
+        self.writeback_batch = Some(n);
+    }
+    /// Writes every value waiting for the read-through store.  See `set_writeback_batch()`.
+    pub fn flush(&mut self) {
+        if let Some(rt) = &self.read_through {
+            rt.flush();
         }
     }
-
-    /// Look up a key in the cache, but do not calculate it if it is not present.
-    pub fn lookup_immut(&self, k: &K) -> Option<V> {
-        self.cache.get(k).and_then(|mv| match mv {
-            MemoVal::InProgress => None,
-            MemoVal::Finished(v) => Some(v),
-        })
+    /// Turns recording of dependency edges on or off.
+    ///
+    /// While tracking is on, every key looked up during the calculation of another key is
+    /// recorded as a dependency of that key, and the recorded edges are kept alongside the cached
+    /// value.  Tracking is off by default, because it costs a clone of every key looked up from
+    /// inside a user function.
+    pub fn set_track_dependencies(&mut self, track: bool) {
+        self.track_dependencies = track;
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    fn fibonacci(mem: &mut Memoizer<usize, usize>, k: &usize) -> usize
+    /// Limits the number of nested lookups a single calculation may make.
+    ///
+    /// If the user function makes more than `n` calls to `lookup()` while calculating one key,
+    /// the whole calculation is abandoned and `try_lookup()` returns
+    /// `LookupError::FanoutExceeded`.  This catches runaway recurrences, such as one with a
+    /// missing base case, before they exhaust memory.
+    pub fn set_max_fanout(&mut self, n: usize) {
+        self.max_fanout = Some(n);
+    }
+    /// Replaces the clock the Memoizer reads the time from.
+    ///
+    /// The default is `SystemClock`, which reads `Instant::now()`.  Every timing the Memoizer
+    /// takes, such as those of `benchmark_caching()`, goes through the clock, so a test can
+    /// pass a closure over a `Cell<Instant>` and advance it by hand to get exact, repeatable
+    /// timings without sleeping.
+    pub fn set_clock<C>(&mut self, clock: C)
+    where
+        C: 'a + Clock,
     {
-        let k = *k;
-        if k < 2 {
+        self.clock = Rc::new(clock);
+    }
+    /// Turns caching on or off.  Caching is on by default.
+    ///
+    /// While caching is off, every `lookup()` calls the user function, and the cache is neither
+    /// read nor written.  Circular dependencies are still detected.  Values cached before caching
+    /// was turned off stay in the cache and are used again once it is turned back on.
+    ///
+    /// This is meant for measuring how much the cache actually helps, and for checking that a
+    /// user function gives the same answers with and without it.
+    pub fn set_caching_enabled(&mut self, enabled: bool) {
+        self.caching_enabled = enabled;
+    }
+    /// Limits the cache to `max_entries` finished entries, evicting the least recently used.
+    ///
+    /// Whenever a value is finished or stored while the cache is full, the finished entry that
+    /// was least recently looked up or stored is removed, and counted in `stats().evictions`.
+    /// Only `lookup()` and its variants count as a use; `lookup_immut()` and iteration don't.
+    /// In-progress entries are never evicted, so a recursion in flight is never disturbed, and
+    /// neither are pinned entries, which can leave the cache over capacity if too many keys are
+    /// pinned.  An evicted entry drops its value but keeps its key, like the entries dropped by
+    /// `clear_values_keep_keys()`, so the key is calculated again on its next lookup and that
+    /// calculation is counted in `stats().re_computations`.
+    ///
+    /// Entries finished before the capacity was set are ranked by the order in which they
+    /// finished, and the cache is trimmed to the new capacity immediately.  This is the same as
+    /// `set_eviction_policy(EvictionPolicy::Lru(max_entries))`.
+    pub fn set_capacity(&mut self, max_entries: usize) {
+        self.policy = EvictionPolicy::Lru(max_entries);
+        self.rerank();
+    }
+    /// Chooses how the cache is bounded.  See `EvictionPolicy` for the choices.
+    ///
+    /// Evictions work as described for `set_capacity()` under every policy.  The entries
+    /// already cached are ranked for the new policy in the order they were last used, or
+    /// finished if no policy was tracking them, and the cache is trimmed to the new bound
+    /// immediately.  Under `Lfu` every entry starts over with a single use.
+    /// `EvictionPolicy::None` lifts any bound, keeping every entry from then on.
+    ///
+    /// Returns `PolicyError::MissingSizeFunction`, and leaves the policy unchanged, if
+    /// `EvictionPolicy::ByteBounded` is chosen before `set_size_function()` has been called.
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) -> Result<(), PolicyError> {
+        if let EvictionPolicy::ByteBounded(_) = policy {
+            if self.size_function.is_none() {
+                return Err(PolicyError::MissingSizeFunction);
+            }
+        }
+        self.policy = policy;
+        self.rerank();
+        Ok(())
+    }
+    /// Returns the policy set with `set_eviction_policy()` or `set_capacity()`.
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+    /// Sets the function that measures values for `EvictionPolicy::ByteBounded`.
+    ///
+    /// The size of a value is measured once, when it is placed in the cache.  If the
+    /// byte-bounded policy is already in effect, the cached values are measured again with the
+    /// new function and the cache is trimmed to fit.
+    pub fn set_size_function<S>(&mut self, size: S)
+    where
+        S: 'a + Fn(&V) -> usize,
+    {
+        self.size_function = Some(Rc::new(size));
+        if let EvictionPolicy::ByteBounded(_) = self.policy {
+            self.rerank();
+        }
+    }
+    // Ranks every finished entry afresh for the current policy, keeping the order in which
+    // they were last used, and then evicts down to the policy's bound.
+    fn rerank(&mut self) {
+        let mut finished: Vec<(u64, u64, K, usize)> = self
+            .cache
+            .iter()
+            .filter_map(|(k, e)| match &e.val {
+                MemoVal::Finished(v) => Some((e.used.1, e.seq, k.clone(), self.size_of(v))),
+                _ => None,
+            })
+            .collect();
+        finished.sort_unstable_by_key(|&(used, seq, _, _)| (used, seq));
+        self.uses.clear();
+        for (_, _, k, size) in finished {
+            let rank = self.next_rank();
+            if let Some(e) = self.cache.get_mut(&k) {
+                e.used = rank;
+            }
+            if rank != UNRANKED {
+                self.uses.insert(rank, k, size);
+            }
+        }
+        self.evict_to_capacity();
+    }
+    // The rank for an entry being placed in the cache now, or `UNRANKED` without a policy.
+    fn next_rank(&mut self) -> Rank {
+        let count = match self.policy {
+            EvictionPolicy::None => return UNRANKED,
+            EvictionPolicy::Lfu(_) => 1,
+            _ => 0,
+        };
+        self.tick += 1;
+        (count, self.tick)
+    }
+    // The size of a value as far as the eviction policy is concerned.
+    fn size_of(&self, v: &V) -> usize {
+        match (&self.policy, &self.size_function) {
+            (EvictionPolicy::ByteBounded(_), Some(size)) => size(v),
+            _ => 0,
+        }
+    }
+    /// Returns the key that would be evicted next to make room, without evicting it.
+    ///
+    /// This is the first finished entry in eviction order that isn't pinned, so pinning the
+    /// key returned here moves the choice on to the next one.  Returns `None` if there is no
+    /// eviction policy, or if every finished entry is pinned.
+    pub fn next_victim(&self) -> Option<&K> {
+        self.uses
+            .ranks
+            .values()
+            .map(|(k, _)| k)
+            .find(|k| !self.cache.peek(k).is_some_and(|e| e.pinned))
+    }
+    /// Counts a use of a finished entry, as a lookup would, without cloning its value.
+    ///
+    /// Under `EvictionPolicy::Lru` this makes the entry the most recently used, protecting the
+    /// key from eviction for as long as a lookup would, for keys that will be needed soon.
+    /// Under `Lfu` it adds one to the entry's use count, and under `Fifo` it does nothing.
+    /// Returns `false`, and does nothing, if `k` does not have a finished value.  Without a
+    /// policy there is no use to count, but the return value is the same.
+    pub fn touch(&mut self, k: &K) -> bool {
+        if !self.is_finished(k) {
+            return false;
+        }
+        self.refresh(k);
+        true
+    }
+    // Counts a use of an entry under the eviction policy, if it is tracking the entry.
+    fn refresh(&mut self, k: &K) {
+        let count = match self.policy {
+            EvictionPolicy::None | EvictionPolicy::Fifo(_) => return,
+            EvictionPolicy::Lfu(_) => 1,
+            _ => 0,
+        };
+        if let Some(e) = self.cache.get_mut(k) {
+            if let Some((key, size)) = self.uses.remove(&e.used) {
+                self.tick += 1;
+                e.used = (e.used.0 + count, self.tick);
+                self.uses.insert(e.used, key, size);
+            }
+        }
+    }
+    /// Sets a second Memoizer that catches the entries evicted by the eviction policy.
+    ///
+    /// Each entry evicted from this cache is moved into `secondary` instead of being dropped,
+    /// and a lookup that misses this cache checks `secondary` before the read-through store and
+    /// the user function.  An entry found there is moved back into this cache, which may in turn
+    /// evict another entry into `secondary`.  Entries only ever live in one of the tiers, so
+    /// `lookup_immut()` and the other read-only methods of this Memoizer don't see the ones in
+    /// `secondary`; use `overflow_tier()` to inspect them.
+    ///
+    /// The user function of `secondary` is never called.  It may have a capacity of its own,
+    /// and even an overflow tier of its own, so tiers can be chained.
+    pub fn set_overflow_tier(&mut self, secondary: Memoizer<'a, K, V>) {
+        self.overflow = Some(Box::new(secondary));
+    }
+    /// Checks that the user function gives the same value when a key is calculated again.
+    ///
+    /// While this is on, about one in four freshly cached keys, picked by a randomly keyed hash
+    /// of the order in which they finish, is calculated a second time right away, and a
+    /// differing value panics.  Each Memoizer picks a different sample, so a key that one run
+    /// doesn't check is likely to be checked by the next.
+    /// This catches user functions that read a clock, draw unseeded random numbers or depend
+    /// on other hidden state at the point where they go wrong, instead of leaving the cache to
+    /// mask the difference.  The second calculation's lookups are counted in `stats()` like any
+    /// others, and mostly hit.  Keys calculated by `try_lookup_with()` are not checked, since
+    /// the user function didn't calculate them.
+    ///
+    /// The check only runs in builds with debug assertions; in release builds this setting has
+    /// no effect.
+    pub fn set_verify_determinism(&mut self, verify: bool)
+    where
+        V: PartialEq,
+    {
+        self.verify_determinism = if verify {
+            Some((Rc::new(|a: &V, b: &V| a == b), RandomState::new()))
+        } else {
+            None
+        };
+    }
+    /// Returns the Memoizer set with `set_overflow_tier()`, if there is one.
+    pub fn overflow_tier(&self) -> Option<&Memoizer<'a, K, V>> {
+        self.overflow.as_deref()
+    }
+    /// Looks up a key in the cache, calculating a value if necessary.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if `try_lookup()` would have returned an error, as it does when a
+    /// circular dependency is detected.
+    ///
+    /// Before the Memoizer starts calculating a value for a particular key, it places an
+    /// "in-progress" marker in the cache for that key.  After that key's value is caculated, the
+    /// "in-progress" marker is replaced with the finished value.  If an in-progress key is passed
+    /// to `lookup()`, this indicates a circular dependency.
+    ///
+    pub fn lookup(&mut self, k: &K) -> V {
+        if self.stack.is_empty() {
+            self.try_lookup(k)
+                .unwrap_or_else(|e| panic!("Memoizer: {}", e))
+        } else {
+            self.lookup_inner(k)
+        }
+    }
+
+    /// Looks up a key in the cache like `lookup()`, but returns an error instead of panicking
+    /// when the calculation has to be abandoned.
+    ///
+    /// A circular dependency gives `LookupError::Cycle`, carrying the key that was looked up
+    /// while it was still being calculated.  This makes it safe to memoize over data-dependent
+    /// graphs which might contain cycles.
+    ///
+    /// When a calculation is abandoned, the in-progress markers of every key whose calculation
+    /// was under way inside this call are removed, so later lookups of those keys start afresh.
+    /// Values finished before the error was detected stay in the cache.  The same cleanup
+    /// happens if the user function panics.
+    ///
+    /// `try_lookup()` may also be called from inside a user function, in which case only the
+    /// calculations started by that call are abandoned.
+    ///
+    /// Abandoning a calculation relies on unwinding, so errors cannot be recovered from when the
+    /// crate is built with `panic = "abort"`.  A user function that catches the unwinding out of
+    /// one of its lookups with `catch_unwind()` doesn't stop the abandonment: the Memoizer
+    /// cleans up after the lookup and resumes it as soon as the user function returns.  Use
+    /// `try_lookup()` inside the user function to recover from an error instead.
+    pub fn try_lookup(&mut self, k: &K) -> Result<V, LookupError<K>> {
+        match self.catch_abandon(|memo| memo.lookup_inner(k)) {
+            Ok(v) => Ok(v),
+            Err(Abandon::Error(e)) => Err(e),
+            Err(other) => self.resume_abandon(other),
+        }
+    }
+
+    /// Looks up a key like `lookup()`, giving up as soon as `cancel` is set.
+    ///
+    /// The flag is checked before every fresh calculation of a key, including the nested ones
+    /// made by the user function, and `Err(Cancelled)` is returned once it is seen to be set.
+    /// Cancellation is cooperative: a user function that is already running is not
+    /// interrupted, but fine-grained recurrences stop promptly.  In-progress markers are cleaned
+    /// up as they are by `try_lookup()`, and values already finished stay cached.
+    ///
+    /// The flag is only borrowed, so it can live on the caller's stack and be set from a scoped
+    /// thread, or from the user function itself.  It must outlive the Memoizer.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `lookup()`.
+    pub fn try_lookup_cancellable(
+        &mut self,
+        k: &K,
+        cancel: &'a AtomicBool,
+    ) -> Result<V, Cancelled> {
+        let outer = self.cancel.replace(cancel);
+        let result = self.catch_abandon(|memo| memo.lookup_inner(k));
+        self.cancel = outer;
+        match result {
+            Ok(v) => Ok(v),
+            Err(Abandon::Cancelled) => Err(Cancelled),
+            Err(other) => self.resume_abandon(other),
+        }
+    }
+
+    /// Looks up a key like `lookup()`, giving up after `fuel` fresh calculations.
+    ///
+    /// Every call to the user function uses one unit of fuel, and cache hits are free.  If the
+    /// lookup needs more calculations than that, `Err(OutOfFuel)` is returned once the fuel is
+    /// used up.  Values finished along the way stay cached, so calling again with more fuel
+    /// resumes where the last call left off.  This lets a long computation be spread over
+    /// several time slices, such as the frames of a game loop.
+    ///
+    /// Only calculations that finish are kept.  The calculations still in progress when the
+    /// fuel runs out are abandoned, so every call should be given more fuel than the longest
+    /// chain of nested lookups, or it may never finish anything.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `lookup()`.
+    pub fn lookup_with_fuel(&mut self, k: &K, fuel: usize) -> Result<V, OutOfFuel> {
+        let outer = self.fuel.replace(fuel);
+        let result = self.catch_abandon(|memo| memo.lookup_inner(k));
+        let left = self.fuel.unwrap_or(0);
+        self.fuel = outer.map(|f| f.saturating_sub(fuel - left));
+        match result {
+            Ok(v) => Ok(v),
+            Err(Abandon::OutOfFuel) => Err(OutOfFuel),
+            Err(other) => self.resume_abandon(other),
+        }
+    }
+
+    // Runs `f`, catching any unwinding out of it and removing the in-progress markers of the
+    // calculations it left unfinished.
+    fn catch_abandon<T, F>(&mut self, f: F) -> Result<T, Abandon<K>>
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let depth = self.stack.len();
+        if depth == 0 && self.abort.is_some() {
+            // `settle()` resumes every abandonment a user function swallows, so this can only
+            // happen if one escaped the Memoizer altogether.
+            self.abort = None;
+            panic!("Memoizer: an abandoned lookup was caught and discarded outside the Memoizer");
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(t) => Ok(t),
+            Err(payload) => {
+                self.unwind_to(depth);
+                match (payload.is::<Abort>(), self.abort.take()) {
+                    (true, Some(a)) => Err(a),
+                    _ => Err(Abandon::Panic(payload)),
+                }
+            }
+        }
+    }
+
+    // Passes an abandonment that the caller cannot report on to the next enclosing catcher, or
+    // panics if there is none.
+    fn resume_abandon(&mut self, a: Abandon<K>) -> ! {
+        match a {
+            Abandon::Panic(payload) => panic::resume_unwind(payload),
+            a if !self.stack.is_empty() => self.abandon(a),
+            Abandon::Error(e) => panic!("Memoizer: {}", e),
+            Abandon::Cancelled => panic!("Memoizer: {}", Cancelled),
+            Abandon::OutOfFuel => panic!("Memoizer: {}", OutOfFuel),
+        }
+    }
+
+    /// Looks up a key, calculating it with the one-off fallible function `f` on a miss instead
+    /// of the user function.
+    ///
+    /// If `f` returns `Ok`, the value is cached as if the user function had calculated it.  If
+    /// it returns `Err`, nothing is cached, the in-progress marker for `k` is removed, and the
+    /// error is returned.  Lookups made by `f` are calculated by the user function as usual.
+    /// This is for keys that need special handling which can fail, where the rest of the keys
+    /// are covered by the user function.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `lookup()`.
+    pub fn try_lookup_with<E, F>(&mut self, k: &K, f: F) -> Result<V, E>
+    where
+        F: FnOnce(&mut Memoizer<'a, K, V>, &K) -> Result<V, E>,
+    {
+        if self.stack.is_empty() {
+            match self.catch_abandon(|memo| memo.lookup_inner_with(k, f)) {
+                Ok(result) => result,
+                Err(a) => self.resume_abandon(a),
+            }
+        } else {
+            self.lookup_inner_with(k, f)
+        }
+    }
+
+    /// Looks up a key with caching turned off for it and every key it looks up in turn, as if
+    /// by `set_caching_enabled(false)` for the duration of the call.
+    ///
+    /// The whole subtree under `k` is calculated from scratch, without reading or writing the
+    /// cache, and the lookups around this call cache as usual.  This is meant to be called
+    /// from a user function, for a what-if branch of a calculation whose values must not leak
+    /// into the cache.  Since the subtree doesn't read the cache, it doesn't see the
+    /// in-progress markers of the calculations around it either: a key that is under way
+    /// outside is calculated again if the subtree reaches it, and a circular dependency
+    /// through such a key is only detected when the subtree reaches the key a second time.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `lookup()`.
+    pub fn lookup_transient(&mut self, k: &K) -> V {
+        let enabled = self.caching_enabled;
+        self.caching_enabled = false;
+        let result = self.catch_abandon(|memo| memo.lookup(k));
+        self.caching_enabled = enabled;
+        match result {
+            Ok(v) => v,
+            Err(a) => self.resume_abandon(a),
+        }
+    }
+
+    /// Looks up a key by evaluating its dependencies bottom-up on an explicit stack, instead of
+    /// through the user function.
+    ///
+    /// `deps` returns the keys a key depends on, and `combine` calculates a key's value from the
+    /// values of its dependencies, given in the same order.  Keys that are already cached are
+    /// used as they are; every other key reached is calculated with `combine` once all of its
+    /// dependencies are, and cached as if by the user function.  Since nothing recurses, the
+    /// native stack doesn't grow with the depth of the recurrence, so chains far too deep for
+    /// `lookup()` can be calculated.  The values of the keys reached are held until the lookup
+    /// returns, since a key's dependencies need not stay cached.
+    ///
+    /// # Panics
+    ///
+    /// Panics like `lookup()` if a circular dependency is detected, either among the keys that
+    /// `deps` describes or with a key already in progress.
+    pub fn lookup_iterative<D, C>(&mut self, k: &K, deps: D, combine: C) -> V
+    where
+        D: Fn(&K) -> Vec<K>,
+        C: Fn(&K, &[V]) -> V,
+    {
+        if self.stack.is_empty() {
+            match self.catch_abandon(|memo| memo.lookup_iterative_inner(k, deps, combine)) {
+                Ok(v) => v,
+                Err(a) => self.resume_abandon(a),
+            }
+        } else {
+            self.lookup_iterative_inner(k, deps, combine)
+        }
+    }
+
+    fn lookup_iterative_inner<D, C>(&mut self, k: &K, deps: D, combine: C) -> V
+    where
+        D: Fn(&K) -> Vec<K>,
+        C: Fn(&K, &[V]) -> V,
+    {
+        let top_level = self.stack.is_empty();
+        let mut index = KeyIndex::new(self.cache.new_index());
+        let mut visits = Vec::new();
+        let mut values: Vec<Option<V>> = Vec::new();
+        // Keys to visit, each with its dependencies once they have been pushed above it.
+        let mut todo: Vec<(usize, Option<Vec<usize>>)> = vec![(index.index(k), None)];
+        while let Some((i, children)) = todo.pop() {
+            visits.resize(index.keys.len(), Visit::Unseen);
+            values.resize(index.keys.len(), None);
+            let key = index.keys[i].clone();
+            match children {
+                None => match visits[i] {
+                    Visit::Done => {}
+                    Visit::Open => self.abandon_cycle(&key),
+                    Visit::Unseen => match self.find(&key, top_level && i == 0) {
+                        Some(v) => {
+                            values[i] = Some(v);
+                            visits[i] = Visit::Done;
+                        }
+                        None => {
+                            visits[i] = Visit::Open;
+                            let ds = deps(&key);
+                            let children: Vec<usize> = ds.iter().map(|d| index.index(d)).collect();
+                            let pending: Vec<usize> = children.iter().rev().cloned().collect();
+                            todo.push((i, Some(children)));
+                            todo.extend(pending.into_iter().map(|c| (c, None)));
+                        }
+                    },
+                },
+                Some(children) => {
+                    let args: Vec<V> = children
+                        .iter()
+                        .map(|&c| {
+                            values[c]
+                                .clone()
+                                .expect("Memoizer: dependency not evaluated")
+                        })
+                        .collect();
+                    let v = combine(&key, &args);
+                    if self.count_miss(&key, top_level && i == 0) {
+                        let recorded = if self.track_dependencies {
+                            children.iter().map(|&c| index.keys[c].clone()).collect()
+                        } else {
+                            Vec::new()
+                        };
+                        self.save_calculated(&key, &v, recorded);
+                    }
+                    values[i] = Some(v);
+                    visits[i] = Visit::Done;
+                }
+            }
+        }
+        values[0].take().expect("Memoizer: key not evaluated")
+    }
+
+    fn lookup_inner(&mut self, k: &K) -> V {
+        let mut calculated = false;
+        let result = self.lookup_inner_with(k, |memo, k| {
+            calculated = true;
+            let user = Rc::clone(&memo.user_function);
+            Ok::<V, Infallible>((*user)(memo, k))
+        });
+        let v = match result {
+            Ok(v) => v,
+            Err(e) => match e {},
+        };
+        if cfg!(debug_assertions) && calculated {
+            self.verify_determinism(k, &v);
+        }
+        v
+    }
+
+    // Calculates a freshly cached key again if it is picked for `set_verify_determinism()`.
+    fn verify_determinism(&mut self, k: &K, v: &V) {
+        let (eq, mut hasher) = match &self.verify_determinism {
+            Some((eq, keys)) => (Rc::clone(eq), keys.build_hasher()),
+            None => return,
+        };
+        let seq = match self.cache.peek(k) {
+            Some(e) if matches!(e.val, MemoVal::Finished(_)) => e.seq,
+            _ => return,
+        };
+        seq.hash(&mut hasher);
+        if hasher.finish() % 4 != 0 {
+            return;
+        }
+        // The calculation gets a frame of its own, so its lookups aren't charged to the caller.
+        self.stack.push(Frame {
+            key: k.clone(),
+            saved: true,
+            known: false,
+            seq: self.seq,
+            lookups: 0,
+            deps: Vec::new(),
+        });
+        let depth = self.stack.len();
+        let user = Rc::clone(&self.user_function);
+        self.stats.computations += 1;
+        let again = (*user)(self, k);
+        self.settle(depth);
+        self.stack.pop();
+        if !eq(v, &again) {
+            panic!(
+                "Memoizer: user function is not deterministic: key {:?} gave {:?} and then {:?}",
+                k, v, again
+            );
+        }
+    }
+
+    fn lookup_inner_with<E, F>(&mut self, k: &K, f: F) -> Result<V, E>
+    where
+        F: FnOnce(&mut Self, &K) -> Result<V, E>,
+    {
+        if let Some(frame) = self.stack.last_mut() {
+            frame.lookups += 1;
+            if self.track_dependencies {
+                frame.deps.push(k.clone());
+            }
+            match self.max_fanout {
+                Some(max_fanout) if frame.lookups > max_fanout => {
+                    let key = frame.key.clone();
+                    self.abandon(Abandon::Error(LookupError::FanoutExceeded {
+                        key,
+                        max_fanout,
+                    }))
+                }
+                _ => {}
+            }
+        }
+        let top_level = self.stack.is_empty();
+        match self.find(k, top_level) {
+            Some(v) => Ok(v),
+            None => self.calculate(k, f),
+        }
+    }
+
+    // Returns the value of `k` if it can be had without calculating it, counting a hit if it
+    // was cached.
+    fn find(&mut self, k: &K, top_level: bool) -> Option<V> {
+        let cached = if self.caching_enabled {
+            // Only the value is cloned, not the whole entry with its recorded dependencies.
+            self.cache.probe(k).map(|e| match &e.val {
+                MemoVal::InProgress => MemoVal::InProgress,
+                MemoVal::Finished(v) => MemoVal::Finished(v.clone()),
+                MemoVal::Known => MemoVal::Known,
+            })
+        } else {
+            None
+        };
+        match cached {
+            Some(MemoVal::Finished(v)) => {
+                self.stats.hits += 1;
+                if top_level {
+                    self.stats.top_level_hits += 1;
+                } else {
+                    self.stats.recursive_hits += 1;
+                }
+                self.refresh(k);
+                Some(v)
+            }
+            Some(MemoVal::InProgress) => self.abandon_cycle(k),
+            Some(MemoVal::Known) | None => self.promote(k).or_else(|| self.read_through(k)),
+        }
+    }
+
+    // Counts a miss, returning whether its value should be cached.
+    fn count_miss(&mut self, k: &K, top_level: bool) -> bool {
+        self.stats.misses += 1;
+        self.stats.computations += 1;
+        if top_level {
+            self.stats.top_level_misses += 1;
+        } else {
+            self.stats.recursive_misses += 1;
+        }
+        let save =
+            self.caching_enabled && self.memo_predicate.as_ref().map(|p| p(k)).unwrap_or(true);
+        if self.caching_enabled && !save {
+            self.stats.predicate_skips += 1;
+        }
+        save
+    }
+
+    fn calculate<E, F>(&mut self, k: &K, f: F) -> Result<V, E>
+    where
+        F: FnOnce(&mut Self, &K) -> Result<V, E>,
+    {
+        if self.cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+            self.abandon(Abandon::Cancelled);
+        }
+        match &mut self.fuel {
+            Some(0) => self.abandon(Abandon::OutOfFuel),
+            Some(fuel) => *fuel -= 1,
+            None => {}
+        }
+        let top_level = self.stack.is_empty();
+        let save = self.count_miss(k, top_level);
+        let mut known = false;
+        if save {
+            match self.cache.get_mut(k) {
+                Some(e) if matches!(e.val, MemoVal::Known) => {
+                    e.val = MemoVal::InProgress;
+                    known = true;
+                    self.stats.re_computations += 1;
+                }
+                Some(_) => panic!("Did not expect to see a memo cacne entry for key {:?}", k),
+                None => {
+                    let _ = self
+                        .cache
+                        .insert(k.clone(), MemoEntry::new(MemoVal::InProgress));
+                }
+            }
+        }
+        if !save && self.in_flight.insert(k.clone(), 0).is_err() {
+            self.abandon_cycle(k);
+        }
+        self.stack.push(Frame {
+            key: k.clone(),
+            saved: save,
+            known,
+            seq: self.seq,
+            lookups: 0,
+            deps: Vec::new(),
+        });
+        self.max_depth = self.max_depth.max(self.stack.len());
+        let depth = self.stack.len();
+        let result = f(self, k);
+        self.settle(depth);
+        let frame = self.stack.pop().expect("Memoizer: lookup stack underflow");
+        let v = match result {
+            Ok(v) => v,
+            Err(e) => {
+                self.drop_frame(frame);
+                return Err(e);
+            }
+        };
+        if save {
+            // A nested `store()` may have finished this key already, or a nested invalidation
+            // may have removed it.  Either way the calculated value replaces what is there.
+            self.save_calculated(k, &v, frame.deps);
+        } else {
+            self.in_flight.remove(k);
+        }
+        Ok(v)
+    }
+
+    // Caches a freshly calculated value and passes it on to the read-through store and to
+    // `lookup_and_collect_new()`.
+    fn save_calculated(&mut self, k: &K, v: &V, deps: Vec<K>) {
+        self.put_finished(k.clone(), v.clone(), deps);
+        self.write_back(k, v);
+        if let Some(collected) = &mut self.collected {
+            collected.push((k.clone(), v.clone()));
+        }
+    }
+
+    // Tries to move a missing value back from the overflow tier.
+    fn promote(&mut self, k: &K) -> Option<V> {
+        if !self.caching_enabled {
+            return None;
+        }
+        let v = self.overflow.as_mut()?.take_finished(k)?;
+        self.store(k.clone(), v.clone());
+        Some(v)
+    }
+
+    // Passes a freshly calculated value to the read-through store, or queues it for the next
+    // batch.
+    fn write_back(&self, k: &K, v: &V) {
+        let rt = match &self.read_through {
+            Some(rt) => rt,
+            None => return,
+        };
+        match self.writeback_batch {
+            Some(n) => {
+                let waiting = {
+                    let mut pending = rt.pending.borrow_mut();
+                    pending.push((k.clone(), v.clone()));
+                    pending.len()
+                };
+                if waiting >= n {
+                    rt.flush();
+                }
+            }
+            None => (rt.write)(k, v),
+        }
+    }
+
+    // Tries to fetch a missing value from the read-through store, caching it if one is found.
+    fn read_through(&mut self, k: &K) -> Option<V> {
+        if !self.caching_enabled {
+            return None;
+        }
+        let v = (self.read_through.as_ref()?.read)(k)?;
+        if self.memo_predicate.as_ref().map(|p| p(k)).unwrap_or(true) {
+            self.store(k.clone(), v.clone());
+        }
+        Some(v)
+    }
+
+    // Replaces the entry for `k` with a finished value, returning the old finished value.
+    fn put_finished(&mut self, k: K, v: V, deps: Vec<K>) -> Option<V> {
+        let pinned = self.cache.peek(&k).is_some_and(|e| e.pinned);
+        let used = self.next_rank();
+        if used != UNRANKED {
+            let size = self.size_of(&v);
+            self.uses.insert(used, k.clone(), size);
+        }
+        let seq = self.next_seq();
+        self.n_finished += 1;
+        let entry = MemoEntry {
+            val: MemoVal::Finished(v),
+            pinned,
+            deps,
+            seq,
+            used,
+        };
+        let old = match self.cache.insert(k, entry) {
+            Ok(()) => None,
+            Err(old) => {
+                self.uses.remove(&old.used);
+                match old.val {
+                    MemoVal::Finished(v) => {
+                        self.n_finished -= 1;
+                        Some(v)
+                    }
+                    _ => None,
+                }
+            }
+        };
+        self.evict_to_capacity();
+        old
+    }
+
+    // Evicts entries in the policy's order until the cache is within its bound.
+    fn evict_to_capacity(&mut self) {
+        let (by_size, bound, load) = match self.policy {
+            EvictionPolicy::None => return,
+            EvictionPolicy::ByteBounded(max_bytes) => (true, max_bytes, self.uses.bytes),
+            EvictionPolicy::Lru(max_entries)
+            | EvictionPolicy::Lfu(max_entries)
+            | EvictionPolicy::Fifo(max_entries) => (false, max_entries, self.uses.len()),
+        };
+        let mut excess = load.saturating_sub(bound);
+        let mut victims = Vec::new();
+        for (k, size) in self.uses.ranks.values() {
+            if excess == 0 {
+                break;
+            }
+            let freed = if by_size { *size } else { 1 };
+            if freed == 0 || self.cache.peek(k).is_some_and(|e| e.pinned) {
+                continue;
+            }
+            excess = excess.saturating_sub(freed);
+            victims.push(k.clone());
+        }
+        for k in victims {
+            // The key is remembered as known, so calculating it again counts as a
+            // re-computation.
+            let e = match self.cache.get_mut(&k) {
+                Some(e) => e,
+                None => continue,
+            };
+            let v = match std::mem::replace(&mut e.val, MemoVal::Known) {
+                MemoVal::Finished(v) => v,
+                val => {
+                    e.val = val;
+                    continue;
+                }
+            };
+            e.deps = Vec::new();
+            self.uses.remove(&e.used);
+            self.n_finished -= 1;
+            e.used = UNRANKED;
+            self.stats.evictions += 1;
+            if let Some(overflow) = &mut self.overflow {
+                overflow.store(k, v);
+            }
+        }
+    }
+
+    // Removes a finished entry, returning its value.
+    fn take_finished(&mut self, k: &K) -> Option<V> {
+        if !self.is_finished(k) {
+            return None;
+        }
+        let e = self.cache.remove(k)?;
+        self.uses.remove(&e.used);
+        self.n_finished -= 1;
+        match e.val {
+            MemoVal::Finished(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn abandon_cycle(&mut self, k: &K) -> ! {
+        let key = k.clone();
+        self.abandon(Abandon::Error(LookupError::Cycle(CycleError { key })))
+    }
+
+    // Unwinds to the nearest enclosing `catch_abandon()`, which reports `a`.
+    fn abandon(&mut self, a: Abandon<K>) -> ! {
+        self.abort = Some(a);
+        panic::resume_unwind(Box::new(Abort))
+    }
+
+    // Restores the stack to `depth` frames when a user function returns from a calculation.
+    //
+    // A user function that catches the unwinding out of one of its own lookups returns with the
+    // frames of the calculations that were cut short still above its own.  They are dropped
+    // here, and if the unwinding was an abandonment, it is resumed, so that it still reaches
+    // the `catch_abandon()` it was meant for.
+    fn settle(&mut self, depth: usize) {
+        self.unwind_to(depth);
+        if let Some(a) = self.abort.take() {
+            self.abandon(a);
+        }
+    }
+
+    // Drops the frames of abandoned calculations along with their in-progress markers.
+    fn unwind_to(&mut self, depth: usize) {
+        while self.stack.len() > depth {
+            let frame = self.stack.pop().expect("Memoizer: lookup stack underflow");
+            self.drop_frame(frame);
+        }
+    }
+
+    // Removes the in-progress marker of a calculation that did not finish, along with any value
+    // stored for its key while it ran.
+    fn drop_frame(&mut self, frame: Frame<K>) {
+        if !frame.saved {
+            self.in_flight.remove(&frame.key);
+            return;
+        }
+        let e = match self.cache.get_mut(&frame.key) {
+            Some(e) => e,
+            None => return,
+        };
+        let abandoned = match e.val {
+            MemoVal::InProgress => true,
+            MemoVal::Finished(_) => e.seq > frame.seq,
+            MemoVal::Known => false,
+        };
+        if !abandoned {
+            return;
+        }
+        self.uses.remove(&e.used);
+        if let MemoVal::Finished(_) = e.val {
+            self.n_finished -= 1;
+        }
+        if frame.known {
+            e.val = MemoVal::Known;
+            e.deps = Vec::new();
+            e.used = UNRANKED;
+        } else {
+            self.cache.remove(&frame.key);
+        }
+    }
+
+    /// Returns the hit and miss counters accumulated so far.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Sets all of the counters returned by `stats()` back to zero.
+    ///
+    /// The cache itself is left alone, so counts taken after a reset describe a warm cache.
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    /// Returns the greatest number of calculations that have been in progress at once, which
+    /// is how deep the user function has recursed.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Formats the stats, the number of finished entries and the maximum depth in the
+    /// Prometheus text exposition format.
+    ///
+    /// Every metric name starts with `prefix` followed by an underscore, so a prefix of
+    /// `"fib"` gives `fib_hits_total`, `fib_misses_total`, `fib_computations_total`,
+    /// `fib_predicate_skips_total`, `fib_evictions_total`, `fib_re_computations_total`,
+    /// `fib_entries` and `fib_max_depth`.
+    /// The prefix must be a valid metric name itself.
+    pub fn prometheus_metrics(&self, prefix: &str) -> String {
+        let metrics = [
+            (
+                "hits_total",
+                "counter",
+                "Lookups answered with a finished value from the cache.",
+                self.stats.hits,
+            ),
+            (
+                "misses_total",
+                "counter",
+                "Lookups that called the user function.",
+                self.stats.misses,
+            ),
+            (
+                "computations_total",
+                "counter",
+                "Values calculated, including the checks made for determinism.",
+                self.stats.computations,
+            ),
+            (
+                "predicate_skips_total",
+                "counter",
+                "Misses not stored because the memoization predicate rejected the key.",
+                self.stats.predicate_skips,
+            ),
+            (
+                "evictions_total",
+                "counter",
+                "Finished values removed to bound the size of the cache.",
+                self.stats.evictions,
+            ),
+            (
+                "re_computations_total",
+                "counter",
+                "Misses on keys whose values had been evicted.",
+                self.stats.re_computations,
+            ),
+            (
+                "entries",
+                "gauge",
+                "Entries with a finished value.",
+                self.len_finished() as u64,
+            ),
+            (
+                "max_depth",
+                "gauge",
+                "Deepest nesting of calculations so far.",
+                self.max_depth as u64,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics.iter() {
+            out.push_str(&format!(
+                "# HELP {p}_{n} {h}\n# TYPE {p}_{n} {k}\n{p}_{n} {v}\n",
+                p = prefix,
+                n = name,
+                h = help,
+                k = kind,
+                v = value
+            ));
+        }
+        out
+    }
+
+    /// Returns the header line for the rows written by `stats_csv_row()`, without a line ending.
+    pub fn stats_csv_header(&self) -> &'static str {
+        "timestamp,hits,misses,len,total_computations,max_depth"
+    }
+
+    /// Formats the stats as a CSV row for a time series, without a line ending.
+    ///
+    /// The columns are named by `stats_csv_header()`.  `timestamp` is written as given, so any
+    /// clock will do.  `len` is the number of finished entries, and `total_computations` is
+    /// `stats().computations`, which unlike `misses` includes the second calculations made by
+    /// `set_verify_determinism()`.
+    pub fn stats_csv_row(&self, timestamp: u64) -> String {
+        format!(
+            "{},{},{},{},{},{}",
+            timestamp,
+            self.stats.hits,
+            self.stats.misses,
+            self.len_finished(),
+            self.stats.computations,
+            self.max_depth
+        )
+    }
+
+    /// Runs `body` and returns its result along with the stats accumulated while it ran.
+    ///
+    /// Lookups made before or after `body` are not counted, so a phase of a computation can be
+    /// measured on its own.  Calls may be nested to measure sub-phases.
+    pub fn with_stats<R, B>(&mut self, body: B) -> (R, CacheStats)
+    where
+        B: FnOnce(&mut Self) -> R,
+    {
+        let before = self.stats;
+        let result = body(self);
+        (result, self.stats.since(&before))
+    }
+
+    /// Looks up a key after calculating the candidates that precede it, bottom-up.
+    ///
+    /// The candidates that come before `k` in the order given to `set_dependency_order()` are
+    /// sorted in that order and looked up one at a time, and then `k` is looked up.  When the
+    /// candidates include all of the dependencies of `k`, every lookup the user function makes
+    /// is a cache hit, so a chain like `f(n)` depending on `f(n - 1)` never recurses deeply.
+    /// Candidates that do not come before `k` are ignored, and so are all of them if no
+    /// dependency order has been set.
+    ///
+    /// This generalizes the usual "fill the table from the bottom up" pattern to any key type
+    /// with a total dependency order.  Use `warm()` when dependencies are only partially ordered.
+    pub fn lookup_ordered<I>(&mut self, k: &K, candidates: I) -> V
+    where
+        I: IntoIterator<Item = K>,
+    {
+        if let Some(order) = self.dependency_order.clone() {
+            let mut before: Vec<K> = candidates
+                .into_iter()
+                .filter(|c| order(c, k) == std::cmp::Ordering::Less)
+                .collect();
+            before.sort_by(|a, b| order(a, b));
+            for c in &before {
+                self.lookup(c);
+            }
+        }
+        self.lookup(k)
+    }
+
+    /// Returns a marker for the current point in this Memoizer's history.
+    ///
+    /// Pass the marker to `entries_since()` to get the entries finished after it was taken.
+    pub fn mark(&self) -> Marker {
+        Marker(self.seq)
+    }
+
+    /// Returns the finished entries whose values were calculated or stored after `m` was taken,
+    /// in the order they were finished.
+    ///
+    /// Each finished value is numbered as it is placed in the cache.  No index of the numbers is
+    /// kept, so this scans the whole cache, and is meant for occasional snapshots of newly
+    /// solved subproblems: take a marker, emit `entries_since()` it, and repeat with a fresh
+    /// marker.  An entry that was recalculated or overwritten after `m` is reported once, in its
+    /// latest position.  Entries removed since then are not reported.  Markers are only
+    /// meaningful for the Memoizer that produced them.
+    pub fn entries_since(&self, m: Marker) -> Vec<(K, V)> {
+        let mut entries: Vec<(u64, K, V)> = self
+            .cache
+            .iter()
+            .filter_map(|(k, e)| match &e.val {
+                MemoVal::Finished(v) if e.seq > m.0 => Some((e.seq, k.clone(), v.clone())),
+                _ => None,
+            })
+            .collect();
+        entries.sort_unstable_by_key(|(seq, _, _)| *seq);
+        entries.into_iter().map(|(_, k, v)| (k, v)).collect()
+    }
+
+    /// Looks up a key, also returning every entry that was newly finished during the lookup.
+    ///
+    /// The new entries are the misses calculated and stored in the cache while `k` was being
+    /// looked up, including `k` itself, in the order they finished.  They are collected as they
+    /// are calculated, so an entry evicted again before the lookup returns is still listed, and
+    /// the cost doesn't depend on the size of the cache.  If `k` was already cached the list is
+    /// empty.  Keys rejected by the memoization predicate are not listed, since they were not
+    /// stored, and neither are values that came from `store()`, the read-through store or the
+    /// overflow tier, since they weren't calculated.  This is meant for incremental checkpoints
+    /// that persist just the delta after each query.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `lookup()`.
+    pub fn lookup_and_collect_new(&mut self, k: &K) -> (V, Vec<(K, V)>) {
+        let outer = self.collected.replace(Vec::new());
+        let result = self.catch_abandon(|memo| memo.lookup_inner(k));
+        let new = std::mem::replace(&mut self.collected, outer).unwrap_or_default();
+        if let Some(collected) = &mut self.collected {
+            collected.extend(new.iter().cloned());
+        }
+        match result {
+            Ok(v) => (v, new),
+            Err(a) => self.resume_abandon(a),
+        }
+    }
+
+    /// Stores the `seed` entries as finished values, then looks up `k`.
+    ///
+    /// This is for callers that already know some intermediate values, so that the calculation
+    /// of `k` uses them instead of calculating them.  The seed entries are stored with `store()`
+    /// and stay cached after the call, like any other entries, and replace any values already
+    /// cached for the same keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `lookup()`.
+    pub fn lookup_with_seed<I>(&mut self, k: &K, seed: I) -> V
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.import(seed);
+        self.lookup(k)
+    }
+
+    /// Looks up `start`, then `next(start)`, and so on, until `stop` accepts a key and its
+    /// value, and returns that key and value.
+    ///
+    /// This is the "find the first term that passes a threshold" loop that search-style
+    /// recurrences are usually driven with.  Each key is looked up with `lookup()`, so the
+    /// sequence is cached along the way and a later call that walks the same keys is mostly
+    /// hits.  If `stop` never accepts a key, this never returns.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `lookup()`.
+    pub fn compute_until<N, S>(&mut self, start: K, next: N, stop: S) -> (K, V)
+    where
+        N: Fn(&K) -> K,
+        S: Fn(&K, &V) -> bool,
+    {
+        let mut k = start;
+        loop {
+            let v = self.lookup(&k);
+            if stop(&k, &v) {
+                return (k, v);
+            }
+            k = next(&k);
+        }
+    }
+
+    /// Looks up each key in turn, returning only the keys that were not already finished.
+    ///
+    /// A key is reported if it was a cache miss at the time of its own top-level lookup, so a
+    /// key that appears twice in `keys` is reported at most once, and keys which were calculated
+    /// only as dependencies of other keys are not reported.
+    pub fn lookup_all_new(&mut self, keys: &[K]) -> Vec<(K, V)> {
+        let mut new = Vec::new();
+        for k in keys {
+            let cached = self.is_finished(k);
+            let v = self.lookup(k);
+            if !cached {
+                new.push((k.clone(), v));
+            }
+        }
+        new
+    }
+
+    /// Returns a closure that looks up keys in this Memoizer.
+    ///
+    /// This lets a memoized function be passed to code that expects a plain closure, such as
+    /// `Iterator::map()`.  The closure holds a mutable borrow of the Memoizer for as long as it
+    /// lives, so the Memoizer cannot be used directly until the closure is dropped.
+    pub fn as_fn_mut<'m>(&'m mut self) -> impl FnMut(&K) -> V + Captures<'a> + 'm {
+        move |k| self.lookup(k)
+    }
+
+    /// Returns a thunk that looks up `k` when it is forced, rather than now.
+    ///
+    /// Nothing is looked up or calculated until `Thunk::force()` is called, and a thunk that is
+    /// dropped unforced leaves the Memoizer untouched.  The thunk holds a mutable borrow of the
+    /// Memoizer until it is forced or dropped, so only one thunk can be outstanding at a time and
+    /// the Memoizer cannot be used directly in the meantime.  To plan several lookups, keep the
+    /// keys and make each thunk as it is needed.
+    pub fn lookup_lazy(&mut self, k: K) -> Thunk<'_, 'a, K, V> {
+        Thunk { key: k, memo: self }
+    }
+
+    /// Calculates the value of a key from scratch, leaving this Memoizer unchanged.
+    ///
+    /// The user function is run against a temporary Memoizer with an empty cache, so the whole
+    /// calculation happens as if nothing had been cached yet.  This is a baseline for comparing
+    /// the cost of a cold calculation against a warm `lookup()`.
+    pub fn compute_cold(&self, k: &K) -> V {
+        self.sibling().lookup(k)
+    }
+
+    /// Stores a value for a key directly, without calling the user function.
+    ///
+    /// Returns the finished value previously stored for `k`, if there was one.  The memoization
+    /// predicate is not consulted, since the value is being placed explicitly.
+    ///
+    /// If `k` is in progress, the stored value is returned by lookups of `k` until the
+    /// calculation of `k` finishes, and then the calculated value replaces it.  If that
+    /// calculation is abandoned instead, the stored value is removed along with the
+    /// in-progress marker, since it was only meant to stand in for the calculated one.
+    pub fn store(&mut self, k: K, v: V) -> Option<V> {
+        let in_progress = self
+            .cache
+            .peek(&k)
+            .is_some_and(|e| matches!(e.val, MemoVal::InProgress));
+        if !in_progress {
+            return self.put_finished(k, v, Vec::new());
+        }
+        // The key is on the stack, so it is left out of `uses` until its calculation finishes.
+        let seq = self.next_seq();
+        if let Some(e) = self.cache.get_mut(&k) {
+            e.val = MemoVal::Finished(v);
+            e.deps.clear();
+            e.seq = seq;
+        }
+        self.n_finished += 1;
+        None
+    }
+
+    /// Look up a key in the cache, but do not calculate it if it is not present.
+    pub fn lookup_immut(&self, k: &K) -> Option<V> {
+        match self.cache.peek(k).map(|e| &e.val) {
+            Some(MemoVal::Finished(v)) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns true if `k` has a finished value, or had one that was dropped by
+    /// `clear_values_keep_keys()` or evicted by the eviction policy.
+    pub fn is_known(&self, k: &K) -> bool {
+        matches!(
+            self.cache.peek(k).map(|e| &e.val),
+            Some(MemoVal::Finished(_)) | Some(MemoVal::Known)
+        )
+    }
+
+    /// Iterates over the finished entries by reference.
+    ///
+    /// In-progress entries are skipped.  With a Memoizer created by `new_ord()` the entries
+    /// come out in ascending key order; with `new_hash()` the order is arbitrary.  To take the
+    /// entries by value, consume the Memoizer with `into_iter()`.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.finished()
+    }
+
+    /// Iterates over the finished entries whose keys match `pred`, by reference.
+    ///
+    /// With a Memoizer created by `new_ord()` the matching entries come out in ascending key
+    /// order, though every entry is still visited to test it.  With `new_hash()` the order is
+    /// arbitrary.  The cache is borrowed for as long as the iterator lives.
+    pub fn iter_where<'s, P>(&'s self, pred: P) -> impl Iterator<Item = (&'s K, &'s V)> + 's
+    where
+        P: 's + Fn(&K) -> bool,
+    {
+        self.finished().filter(move |(k, _)| pred(k))
+    }
+
+    /// Returns the set of keys with a finished value.
+    ///
+    /// In-progress keys and keys rejected by the memoization predicate are not included, which
+    /// makes this a convenient way to assert exactly what a computation left in the cache.
+    pub fn finished_keys(&self) -> HashSet<K>
+    where
+        K: Hash + Eq,
+    {
+        self.finished().map(|(k, _)| k.clone()).collect()
+    }
+
+    /// Returns the number of entries with a finished value.
+    ///
+    /// Keys whose values were dropped by `clear_values_keep_keys()` are not counted until they
+    /// are calculated again.
+    pub fn len_finished(&self) -> usize {
+        self.n_finished
+    }
+
+    /// Returns the keys whose calculations are under way, outermost first.
+    ///
+    /// This is the chain of nested lookups that led to the current calculation, so calling
+    /// it from a user function shows how that key was reached.  Outside of a lookup it is
+    /// empty.
+    pub fn in_progress_keys_snapshot(&self) -> Vec<K> {
+        self.stack
+            .iter()
+            .filter(|frame| {
+                // A frame re-checking a finished key for `set_verify_determinism()` is not in
+                // progress.
+                !frame.saved
+                    || self
+                        .cache
+                        .peek(&frame.key)
+                        .is_some_and(|e| matches!(e.val, MemoVal::InProgress))
+            })
+            .map(|frame| frame.key.clone())
+            .collect()
+    }
+
+    /// Returns the number of entries with a finished value, the same as `len_finished()`.
+    pub fn len(&self) -> usize {
+        self.len_finished()
+    }
+
+    /// Returns true if no entry has a finished value.
+    pub fn is_empty(&self) -> bool {
+        self.n_finished == 0
+    }
+
+    /// Returns true if `k` has a finished value in the cache.
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.is_finished(k)
+    }
+
+    /// Removes the finished value of a key, returning it if there was one.
+    ///
+    /// The next lookup of `k` calculates it again.  Entries that depend on `k` are left alone;
+    /// use `invalidate_and_recompute()` to replace them as well.  The key is removed from the
+    /// overflow tier too, if there is one.
+    ///
+    /// A key that is in progress is left untouched and `None` is returned, since removing its
+    /// marker would hide a circular dependency from the calculation under way.  Invalidating it
+    /// once the outermost lookup has returned has the intended effect.
+    pub fn invalidate(&mut self, k: &K) -> Option<V> {
+        let secondary = self.overflow.as_mut().and_then(|o| o.invalidate(k));
+        self.take_finished(k).or(secondary)
+    }
+
+    /// Invalidates each of `keys` in turn, returning their values in the same order.
+    ///
+    /// Each key is handled like `invalidate()`, so keys that are absent or in progress give
+    /// `None` and are left untouched, and a key listed twice gives `None` the second time.
+    pub fn remove_many(&mut self, keys: &[K]) -> Vec<Option<V>> {
+        keys.iter().map(|k| self.invalidate(k)).collect()
+    }
+
+    /// Removes every entry from the cache, keeping the user function and all settings.
+    ///
+    /// Pinned entries and the keys remembered by `clear_values_keep_keys()` are removed along
+    /// with everything else, and so is the content of the overflow tier.  In-progress markers
+    /// are kept, so clearing from inside a user function leaves the calculations under way
+    /// intact; they store their values as they finish.  The stats are not reset.
+    pub fn clear(&mut self) {
+        let removed: Vec<K> = self
+            .cache
+            .iter()
+            .filter(|(_, e)| !matches!(e.val, MemoVal::InProgress))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in removed {
+            self.cache.remove(&k);
+        }
+        self.uses.clear();
+        self.n_finished = 0;
+        if let Some(overflow) = &mut self.overflow {
+            overflow.clear();
+        }
+    }
+
+    /// Drops every finished value while remembering which keys had one.
+    ///
+    /// Each unpinned finished entry is replaced with a marker that holds no value, so
+    /// `is_known()` still returns true for its key, but `lookup_immut()` returns `None` and
+    /// `lookup()` calculates the value again.  Recorded dependencies are dropped along with the
+    /// values.  Pinned and in-progress entries are left alone.
+    ///
+    /// This frees the memory held by values while keeping a record of the visited keys, for
+    /// algorithms that need the values in one pass and only the set of keys in the next.
+    pub fn clear_values_keep_keys(&mut self) {
+        for e in self.cache.values_mut() {
+            if let MemoVal::Finished(_) = e.val {
+                if !e.pinned {
+                    e.val = MemoVal::Known;
+                    e.deps = Vec::new();
+                    self.uses.remove(&e.used);
+                    self.n_finished -= 1;
+                    e.used = UNRANKED;
+                    self.stats.evictions += 1;
+                }
+            }
+        }
+    }
+
+    /// Pins a finished entry, exempting it from eviction.
+    ///
+    /// Pinned entries are never removed by `retain()` or `retain_top_k()`, and they do not count
+    /// towards the number of entries `retain_top_k()` keeps.  Returns `false` if `k` does not
+    /// have a finished value in the cache.
+    pub fn pin(&mut self, k: &K) -> bool {
+        self.set_pinned(k, true)
+    }
+
+    /// Unpins an entry pinned with `pin()`, making it subject to eviction again.
+    ///
+    /// Returns `false` if `k` does not have a finished value in the cache.
+    pub fn unpin(&mut self, k: &K) -> bool {
+        self.set_pinned(k, false)
+    }
+
+    /// Invalidates a key along with everything that transitively depends on it, then
+    /// recomputes all of the invalidated entries.
+    ///
+    /// Dependents are found through the edges recorded while `set_track_dependencies(true)` was
+    /// in effect.  Entries calculated without tracking have no recorded dependencies, so only
+    /// `k` itself is invalidated for them.  In-progress entries are left untouched.
+    ///
+    /// This is meant for incremental recomputation after an input of the user function changed:
+    /// call it with the key that reads the changed input.
+    pub fn invalidate_and_recompute(&mut self, k: &K) {
+        let (index, edges) = self.dependency_graph();
+        let mut dependents = vec![Vec::new(); index.keys.len()];
+        for (from, tos) in edges.iter().enumerate() {
+            for &to in tos {
+                dependents[to].push(from);
+            }
+        }
+        let mut seen = vec![false; index.keys.len()];
+        let mut todo: Vec<usize> = index.get(k).into_iter().collect();
+        let mut invalid = Vec::new();
+        while let Some(i) = todo.pop() {
+            if !seen[i] {
+                seen[i] = true;
+                invalid.push(i);
+                todo.extend(&dependents[i]);
+            }
+        }
+        let mut removed = Vec::new();
+        for i in invalid {
+            let key = &index.keys[i];
+            if self.is_finished(key) {
+                if let Some(e) = self.cache.remove(key) {
+                    self.uses.remove(&e.used);
+                    self.n_finished -= 1;
+                }
+                removed.push(key.clone());
+            }
+        }
+        for key in removed {
+            self.lookup(&key);
+        }
+    }
+
+    /// Returns true if the recorded dependency edges contain a cycle.
+    ///
+    /// See `find_cycle()`.
+    pub fn has_cycle(&self) -> bool {
+        self.find_cycle().is_some()
+    }
+
+    /// Searches the recorded dependency edges for a cycle, returning one if it exists.
+    ///
+    /// Edges are only recorded while `set_track_dependencies(true)` is in effect.  The returned
+    /// path starts at a key and follows dependency edges, each key depending on the next, until
+    /// the last key, which depends on the first.
+    ///
+    /// A single calculation can never record a cycle, since it would have been detected as a
+    /// circular dependency.  Cycles appear when entries are invalidated and recalculated under
+    /// a recurrence that has changed, which means the recurrence is not a consistent function
+    /// of its inputs.
+    pub fn find_cycle(&self) -> Option<Vec<K>> {
+        let (index, edges) = self.dependency_graph();
+        let mut visits = vec![Visit::Unseen; index.keys.len()];
+        for root in 0..index.keys.len() {
+            if visits[root] != Visit::Unseen {
+                continue;
+            }
+            visits[root] = Visit::Open;
+            let mut stack = vec![(root, 0)];
+            while let Some((i, next)) = stack.last_mut() {
+                match edges[*i].get(*next) {
+                    None => {
+                        visits[*i] = Visit::Done;
+                        stack.pop();
+                    }
+                    Some(&j) => {
+                        *next += 1;
+                        match visits[j] {
+                            Visit::Done => {}
+                            Visit::Unseen => {
+                                visits[j] = Visit::Open;
+                                stack.push((j, 0));
+                            }
+                            Visit::Open => {
+                                let start = stack.iter().position(|&(s, _)| s == j).unwrap();
+                                let path = stack[start..]
+                                    .iter()
+                                    .map(|&(s, _)| index.keys[s].clone())
+                                    .collect();
+                                return Some(path);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the number of keys on the longest chain of recorded dependencies.
+    ///
+    /// See `critical_path_keys()`.
+    pub fn critical_path(&self) -> usize {
+        self.critical_path_keys().len()
+    }
+
+    /// Returns the longest chain of finished keys in which each key depends on the next.
+    ///
+    /// Edges are only recorded while `set_track_dependencies(true)` is in effect, and a
+    /// dependency that is no longer finished ends the chain.  Every key on the chain has to be
+    /// calculated after the one following it, so its length bounds how far the calculation could
+    /// be parallelized: a cache whose critical path is nearly as long as the cache itself gains
+    /// little from `SyncMemoizer::compute_layers()`.
+    ///
+    /// If the recorded edges contain a cycle (see `find_cycle()`), the edges closing it are
+    /// ignored.  An empty cache has an empty critical path, and a cache without recorded edges
+    /// has a critical path of one key.
+    pub fn critical_path_keys(&self) -> Vec<K> {
+        let (index, edges) = self.dependency_graph();
+        let finished: Vec<bool> = index.keys.iter().map(|k| self.is_finished(k)).collect();
+        let mut visits = vec![Visit::Unseen; index.keys.len()];
+        // The length of the longest chain starting at each key, and the key after it.
+        let mut longest = vec![(0, None); index.keys.len()];
+        for root in 0..index.keys.len() {
+            if !finished[root] || visits[root] != Visit::Unseen {
+                continue;
+            }
+            visits[root] = Visit::Open;
+            let mut stack = vec![(root, 0)];
+            while let Some((i, next)) = stack.last_mut() {
+                let i = *i;
+                match edges[i].get(*next) {
+                    None => {
+                        let mut best = (1, None);
+                        for &j in &edges[i] {
+                            if visits[j] == Visit::Done && longest[j].0 + 1 > best.0 {
+                                best = (longest[j].0 + 1, Some(j));
+                            }
+                        }
+                        longest[i] = best;
+                        visits[i] = Visit::Done;
+                        stack.pop();
+                    }
+                    Some(&j) => {
+                        *next += 1;
+                        if finished[j] && visits[j] == Visit::Unseen {
+                            visits[j] = Visit::Open;
+                            stack.push((j, 0));
+                        }
+                    }
+                }
+            }
+        }
+        let mut path = Vec::new();
+        let mut at = (0..index.keys.len())
+            .filter(|&i| visits[i] == Visit::Done)
+            .fold(None, |best: Option<usize>, i| match best {
+                Some(b) if longest[b].0 >= longest[i].0 => Some(b),
+                _ => Some(i),
+            });
+        while let Some(i) = at {
+            path.push(index.keys[i].clone());
+            at = longest[i].1;
+        }
+        path
+    }
+
+    // Indexes every key that is cached or recorded as a dependency, and lists each finished
+    // entry's recorded dependencies by index.
+    fn dependency_graph(&self) -> (KeyIndex<'a, K>, Vec<Vec<usize>>) {
+        let mut index = KeyIndex::new(self.cache.new_index());
+        let mut edges = Vec::new();
+        for (key, e) in self.cache.iter() {
+            if let MemoVal::Finished(_) = e.val {
+                let from = index.index(key);
+                let tos: Vec<usize> = e.deps.iter().map(|d| index.index(d)).collect();
+                edges.resize(index.keys.len(), Vec::new());
+                edges[from] = tos;
+            }
+        }
+        edges.resize(index.keys.len(), Vec::new());
+        (index, edges)
+    }
+
+    /// Calculates the targets and all of their transitive dependencies bottom-up.
+    ///
+    /// `deps` must return the keys that the user function looks up for a given key.  The keys
+    /// reachable from `targets` are sorted topologically and then looked up in dependency order,
+    /// so every lookup made by the user function is a cache hit and the native stack never grows
+    /// deeper than one calculation.  Keys which are already finished are not explored further.
+    ///
+    /// If `deps` describes a cycle, a `CycleError` is returned before anything is calculated.
+    pub fn warm<D>(&mut self, targets: &[K], deps: D) -> Result<(), CycleError<K>>
+    where
+        D: Fn(&K) -> Vec<K>,
+    {
+        let mut index = KeyIndex::new(self.cache.new_index());
+        let mut visits = Vec::new();
+        let mut order = Vec::new();
+        for target in targets {
+            let mut stack: Vec<(usize, Vec<K>)> = Vec::new();
+            let mut next = Some(target.clone());
+            loop {
+                if let Some(key) = next.take() {
+                    let i = index.index(&key);
+                    visits.resize(index.keys.len(), Visit::Unseen);
+                    match visits[i] {
+                        Visit::Done => {}
+                        Visit::Open => return Err(CycleError { key }),
+                        Visit::Unseen if self.is_finished(&key) => visits[i] = Visit::Done,
+                        Visit::Unseen => {
+                            visits[i] = Visit::Open;
+                            let mut children = deps(&key);
+                            children.reverse();
+                            stack.push((i, children));
+                        }
+                    }
+                }
+                match stack.last_mut() {
+                    None => break,
+                    Some((i, children)) => match children.pop() {
+                        Some(child) => next = Some(child),
+                        None => {
+                            visits[*i] = Visit::Done;
+                            order.push(*i);
+                            stack.pop();
+                        }
+                    },
+                }
+            }
+        }
+        for i in order {
+            self.lookup(&index.keys[i]);
+        }
+        Ok(())
+    }
+
+    /// Consumes the Memoizer, keeping its finished entries in a compact read-only form.
+    ///
+    /// The entries are moved, not cloned, into a vector sorted by key, which is searched by
+    /// bisection.  That uses less memory than either map backend, and `FrozenMemoizer::get()`
+    /// returns references, which suits a serve-only phase after the calculations are done.
+    /// In-progress entries are dropped.
+    pub fn freeze(self) -> FrozenMemoizer<K, V>
+    where
+        K: Ord,
+    {
+        FrozenMemoizer::from_entries(self.into_iter().collect())
+    }
+
+    /// Returns every key that calculating the targets would need, but which is not finished.
+    ///
+    /// `deps` must return the keys that the user function looks up for a given key, as for
+    /// `warm()`.  The walk starts at the targets and follows `deps` from every key that is not
+    /// finished; finished keys are not explored, since their dependencies will never be looked
+    /// up.  Each missing key is listed once, in the order it was first reached, so the list
+    /// can be fetched from an external store in one batch before the targets are looked up.
+    pub fn missing_dependencies<D>(&self, targets: &[K], deps: D) -> Vec<K>
+    where
+        D: Fn(&K) -> Vec<K>,
+    {
+        let mut index = KeyIndex::new(self.cache.new_index());
+        let mut todo: Vec<K> = targets.iter().rev().cloned().collect();
+        let mut missing = Vec::new();
+        while let Some(key) = todo.pop() {
+            if index.get(&key).is_some() {
+                continue;
+            }
+            index.index(&key);
+            if !self.is_finished(&key) {
+                todo.extend(deps(&key).into_iter().rev());
+                missing.push(key);
+            }
+        }
+        missing
+    }
+
+    /// Consumes the Memoizer, transforming the keys and values of its finished entries and
+    /// storing them in `dest`, which is returned.
+    ///
+    /// `dest` supplies the backend and the user function for the new representation, and may
+    /// already hold entries.  Each transformed entry is placed with `store()`, so it replaces
+    /// any value `dest` had for the same key.  If `kf` maps two keys to the same new key, only
+    /// one of the two values is kept, and which one is unspecified.  In-progress entries are
+    /// dropped.
+    pub fn map_into<'b, L, W, KF, VF>(
+        self,
+        kf: KF,
+        vf: VF,
+        mut dest: Memoizer<'b, L, W>,
+    ) -> Memoizer<'b, L, W>
+    where
+        L: 'b + Clone + Debug,
+        W: 'b + Clone + Debug,
+        KF: Fn(&K) -> L,
+        VF: Fn(&V) -> W,
+    {
+        for (k, v) in self.into_iter() {
+            dest.store(kf(&k), vf(&v));
+        }
+        dest
+    }
+
+    /// Returns a sorted map of the finished entries, leaving the cache untouched.
+    ///
+    /// This gives sorted output from a Memoizer created with `new_hash()`, so the hash backend
+    /// can still be used while calculating.
+    pub fn to_sorted_map(&self) -> BTreeMap<K, V>
+    where
+        K: Ord,
+    {
+        self.finished()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Makes equal finished values share storage.
+    ///
+    /// Every finished value is replaced with a clone of the first equal value found.  This only
+    /// has an effect when `V` is itself a shared pointer such as `Rc<T>` or `Arc<T>`, whose
+    /// clones share one allocation.  For any other value type the clone is a separate copy, so
+    /// nothing is saved and the pass is wasted work.
+    pub fn dedup_values(&mut self)
+    where
+        V: Eq + Hash,
+    {
+        let mut canonical = HashSet::new();
+        for e in self.cache.values_mut() {
+            if let MemoVal::Finished(v) = &mut e.val {
+                match canonical.get(v) {
+                    Some(c) => *v = V::clone(c),
+                    None => {
+                        canonical.insert(v.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves every finished entry with a key greater than or equal to `key` into a new Memoizer.
+    ///
+    /// The new Memoizer shares this one's user function and memoization predicate.  Like
+    /// `BTreeMap::split_off()`, the upper partition is returned and the lower one stays in
+    /// `self`.  In-progress entries are never moved.
+    pub fn split_off(&mut self, key: &K) -> Memoizer<'a, K, V>
+    where
+        K: Ord,
+    {
+        let mut upper = self.sibling();
+        // The moved entries keep their sequence numbers, so `upper` numbers new ones after them.
+        upper.seq = self.seq;
+        let moved: Vec<K> = self
+            .finished()
+            .filter(|(k, _)| *k >= key)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in moved {
+            if let Some(mut e) = self.cache.remove(&k) {
+                self.uses.remove(&e.used);
+                self.n_finished -= 1;
+                e.used = UNRANKED;
+                upper.n_finished += 1;
+                let _ = upper.cache.insert(k, e);
+            }
+        }
+        upper
+    }
+
+    /// Returns a digest of every finished entry, independent of the order they are stored in.
+    ///
+    /// Each `(key, value)` pair is hashed on its own and the hashes are combined with XOR, so
+    /// two Memoizers holding the same finished entries have the same digest whatever their
+    /// backends.  This is a cheap determinism check: run the same calculation twice and compare
+    /// the digests instead of the full contents.  Equal digests don't prove equal contents, but
+    /// differing entries collide only by chance.  The digest uses `DefaultHasher`, so it can
+    /// change between Rust releases and should not be stored.
+    pub fn state_hash(&self) -> u64
+    where
+        K: Hash,
+        V: Hash,
+    {
+        self.finished()
+            .map(|entry| {
+                let mut hasher = DefaultHasher::new();
+                entry.hash(&mut hasher);
+                hasher.finish()
+            })
+            .fold(0, |acc, h| acc ^ h)
+    }
+
+    /// Measures how much caching helps with a set of queries.
+    ///
+    /// The keys are first looked up in a throwaway Memoizer with the same user function and
+    /// caching turned off, and then in this one, timing both runs and counting the calls to
+    /// the user function in each.  The run on this Memoizer uses whatever it has cached, and
+    /// leaves the values it calculates cached.
+    ///
+    /// The run without caching calls the user function once for every path through the
+    /// recurrence, which for many recurrences is exponential in the size of the key, so keep
+    /// the keys small.  If only the cached run is too quick to measure, the speedup is
+    /// infinite, and if both are, or `keys` is empty, it is 1.  Both runs are timed with the
+    /// clock set by `set_clock()`.
+    pub fn benchmark_caching(&mut self, keys: &[K]) -> CacheBenefit {
+        if keys.is_empty() {
+            return CacheBenefit {
+                speedup: 1.0,
+                avoided_computations: 0,
+            };
+        }
+        let mut cold = self.sibling();
+        cold.set_caching_enabled(false);
+        let start = self.clock.now();
+        for k in keys {
+            cold.lookup(k);
+        }
+        let cold_time = self.clock.now().saturating_duration_since(start);
+        let misses = self.stats.misses;
+        let start = self.clock.now();
+        for k in keys {
+            self.lookup(k);
+        }
+        let warm_time = self.clock.now().saturating_duration_since(start);
+        let warm_computations = self.stats.misses - misses;
+        let speedup = if warm_time.is_zero() {
+            if cold_time.is_zero() {
+                1.0
+            } else {
+                f64::INFINITY
+            }
+        } else {
+            cold_time.as_secs_f64() / warm_time.as_secs_f64()
+        };
+        CacheBenefit {
+            speedup,
+            avoided_computations: cold.stats.misses.saturating_sub(warm_computations) as usize,
+        }
+    }
+
+    /// Compares the finished entries of this Memoizer with those of `other`.
+    ///
+    /// This is meant for regression testing: fill one Memoizer using the old version of a
+    /// recurrence and one using the new version, and check that the diff is empty.  Entries that
+    /// are in progress in either Memoizer count as missing.  Each list of keys is in the
+    /// iteration order of the Memoizer the keys were found in.
+    pub fn diff(&self, other: &Memoizer<'_, K, V>) -> CacheDiff<K, V>
+    where
+        V: PartialEq,
+    {
+        let mut diff = CacheDiff {
+            only_self: Vec::new(),
+            only_other: Vec::new(),
+            differing: Vec::new(),
+        };
+        for (k, v) in self.finished() {
+            match other.cache.peek(k).map(|e| &e.val) {
+                Some(MemoVal::Finished(ov)) if ov == v => {}
+                Some(MemoVal::Finished(ov)) => {
+                    diff.differing.push((k.clone(), v.clone(), ov.clone()))
+                }
+                _ => diff.only_self.push(k.clone()),
+            }
+        }
+        for (k, _) in other.finished() {
+            if !self.is_finished(k) {
+                diff.only_other.push(k.clone());
+            }
+        }
+        diff
+    }
+
+    /// Counts the finished entries by a projection of their keys.
+    ///
+    /// Each finished key is mapped through `project`, and the result maps each bucket to the
+    /// number of keys that landed in it.
+    pub fn histogram<B, P>(&self, project: P) -> HashMap<B, usize>
+    where
+        B: Hash + Eq,
+        P: Fn(&K) -> B,
+    {
+        let mut counts = HashMap::new();
+        for (k, _) in self.finished() {
+            *counts.entry(project(k)).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Returns the sum of all finished values, or `None` if there are none.
+    pub fn sum(&self) -> Option<V>
+    where
+        V: std::iter::Sum,
+    {
+        let mut values = self.finished().map(|(_, v)| v.clone()).peekable();
+        values.peek()?;
+        Some(values.sum())
+    }
+
+    /// Returns the smallest finished value, or `None` if there are none.
+    pub fn min(&self) -> Option<V>
+    where
+        V: Ord,
+    {
+        self.finished().map(|(_, v)| v).min().cloned()
+    }
+
+    /// Returns the largest finished value, or `None` if there are none.
+    pub fn max(&self) -> Option<V>
+    where
+        V: Ord,
+    {
+        self.finished().map(|(_, v)| v).max().cloned()
+    }
+
+    /// Writes every finished entry to `w`, one entry per line.
+    ///
+    /// Each line holds the key's `Display` output, a tab, and the value's `Display` output.
+    /// Since neither could be read back with `load_from_path()` if it contained a tab or a line
+    /// break, an entry whose key or value does is an `InvalidInput` error, and the entries
+    /// before it are left written.
+    pub fn write_entries<W: Write>(&self, w: W) -> io::Result<()>
+    where
+        K: Display,
+        V: Display,
+    {
+        self.write_entries_where(w, |_, _| true)
+    }
+
+    /// Writes the finished entries matching `pred` to `w`, in the `write_entries()` format.
+    ///
+    /// This is for checkpointing just the part of a large cache that is worth keeping.
+    pub fn write_entries_where<W, P>(&self, mut w: W, pred: P) -> io::Result<()>
+    where
+        K: Display,
+        V: Display,
+        W: Write,
+        P: Fn(&K, &V) -> bool,
+    {
+        for (k, v) in self.finished().filter(|(k, v)| pred(k, v)) {
+            let (k, v) = (k.to_string(), v.to_string());
+            if [&k, &v].iter().any(|s| s.contains(['\t', '\n', '\r'])) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("entry for key {:?} contains a tab or a line break", k),
+                ));
+            }
+            writeln!(w, "{}\t{}", k, v)?;
+        }
+        w.flush()
+    }
+
+    /// Writes the finished entries to `w` as CSV, for analysis in other tools.
+    ///
+    /// The first row is the header `key,value`, followed by one row per finished entry holding
+    /// the `Display` output of the key and the value.  A field containing a comma, a double
+    /// quote or a line break is quoted, with any double quotes doubled, as RFC 4180 describes.
+    /// Composite keys are written as a single field, so a key type meant for CSV export should
+    /// display its components in a form the reading tool can split.
+    pub fn write_csv<W: Write>(&self, mut w: W) -> io::Result<()>
+    where
+        K: Display,
+        V: Display,
+    {
+        writeln!(w, "key,value")?;
+        for (k, v) in self.finished() {
+            let k = csv_field(k.to_string());
+            let v = csv_field(v.to_string());
+            writeln!(w, "{},{}", k, v)?;
+        }
+        w.flush()
+    }
+
+    /// Atomically replaces the file at `path` with a checkpoint of the finished entries.
+    ///
+    /// The entries are written in the `write_entries()` format to a temporary file next to
+    /// `path`, which is synced to disk and then renamed over `path`, and on Unix the directory
+    /// is synced as well so the rename itself is durable.  A crash part way through leaves
+    /// either the old checkpoint or the new one, never a partial file, provided the filesystem
+    /// renames atomically.  The temporary file's name is unique to the process and the call, so
+    /// concurrent checkpoints to the same path don't write over each other's files, and it is
+    /// removed if writing fails.
+    pub fn checkpoint_to_path<P: AsRef<Path>>(&self, path: P) -> io::Result<()>
+    where
+        K: Display,
+        V: Display,
+    {
+        static CHECKPOINTS: AtomicU64 = AtomicU64::new(0);
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            CHECKPOINTS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = File::create(&tmp).and_then(|file| {
+            let mut w = BufWriter::new(file);
+            self.write_entries(&mut w)?;
+            w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&tmp, path)
+        });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        #[cfg(unix)]
+        {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Returns every finished entry, in the same order as `iter()`.
+    ///
+    /// In-progress entries are left out, since they mean nothing outside the lookup that placed
+    /// them.  Pass the result to `import()` or a `from_entries_*()` constructor to rebuild the
+    /// cache, possibly in another run of the program.
+    pub fn export(&self) -> Vec<(K, V)> {
+        self.finished()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Stores every entry from `entries` with `store()`, as if each value had been calculated.
+    pub fn import<I>(&mut self, entries: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        for (k, v) in entries {
+            self.store(k, v);
+        }
+    }
+
+    /// Stores every entry from a file written by `checkpoint_to_path()` or `write_entries()`.
+    ///
+    /// Entries replace any finished values already cached for the same keys.  Returns the
+    /// number of entries loaded, or an `InvalidData` error naming the first line that could not
+    /// be parsed.
+    pub fn load_from_path<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize>
+    where
+        K: FromStr,
+        V: FromStr,
+    {
+        let r = BufReader::new(File::open(path)?);
+        let mut count = 0;
+        for (n, line) in r.lines().enumerate() {
+            let line = line?;
+            let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("line {}", n + 1));
+            let (k, v) = line.split_once('\t').ok_or_else(bad)?;
+            let k = k.parse().map_err(|_| bad())?;
+            let v = v.parse().map_err(|_| bad())?;
+            self.store(k, v);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn finished(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.cache.iter().filter_map(|(k, e)| match &e.val {
+            MemoVal::Finished(v) => Some((k, v)),
+            _ => None,
+        })
+    }
+
+    fn is_finished(&self, k: &K) -> bool {
+        matches!(
+            self.cache.peek(k).map(|e| &e.val),
+            Some(MemoVal::Finished(_))
+        )
+    }
+
+    fn set_pinned(&mut self, k: &K, pinned: bool) -> bool {
+        match self.cache.get_mut(k) {
+            Some(
+                e @ MemoEntry {
+                    val: MemoVal::Finished(_),
+                    ..
+                },
+            ) => {
+                e.pinned = pinned;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Keeps the `k` highest-scoring finished entries and removes all the others.
+    ///
+    /// Every finished entry is scored with `score`, and scores are compared with
+    /// `f64::total_cmp`.  Ties at the cutoff are broken arbitrarily.  In-progress and pinned
+    /// entries are never removed.
+    ///
+    /// This is meant to be called at checkpoints to bound the size of the cache, as a batch
+    /// alternative to evicting entries one at a time.  Returns the number of entries removed.
+    pub fn retain_top_k<S>(&mut self, k: usize, score: S) -> usize
+    where
+        S: Fn(&K, &V) -> f64,
+    {
+        let mut scored: Vec<(f64, K)> = self
+            .cache
+            .iter()
+            .filter_map(|(key, e)| match &e.val {
+                MemoVal::Finished(v) if !e.pinned => Some((score(key, v), key.clone())),
+                _ => None,
+            })
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let removed: Vec<K> = scored.into_iter().skip(k).map(|(_, key)| key).collect();
+        self.remove_finished(removed)
+    }
+
+    /// Keeps only the finished entries for which `keep` returns true.
+    ///
+    /// In-progress and pinned entries are never removed.  Returns the number of entries
+    /// removed, which can be used to decide whether a cleanup pass was worthwhile.
+    pub fn retain<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let removed: Vec<K> = self
+            .cache
+            .iter()
+            .filter_map(|(key, e)| match &e.val {
+                MemoVal::Finished(v) if !e.pinned && !keep(key, v) => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+        self.remove_finished(removed)
+    }
+
+    /// Removes every finished entry that can't be reached from `roots` through `neighbors`.
+    ///
+    /// This is garbage collection over a graph the caller already knows, rather than over the
+    /// dependencies recorded with `set_track_dependencies()`.  The walk follows `neighbors`
+    /// from every reached key, whether or not it is cached.  In-progress and pinned entries are
+    /// never removed.  Returns the number of entries removed.
+    pub fn retain_reachable<N>(&mut self, roots: &[K], neighbors: N) -> usize
+    where
+        N: Fn(&K) -> Vec<K>,
+    {
+        let mut reached = KeyIndex::new(self.cache.new_index());
+        let mut todo = roots.to_vec();
+        while let Some(key) = todo.pop() {
+            if reached.get(&key).is_none() {
+                reached.index(&key);
+                todo.extend(neighbors(&key));
+            }
+        }
+        self.retain(|k, _| reached.get(k).is_some())
+    }
+
+    fn remove_finished(&mut self, keys: Vec<K>) -> usize {
+        let count = keys.len();
+        for key in keys {
+            self.take_finished(&key);
+        }
+        self.stats.evictions += count as u64;
+        count
+    }
+}
+
+/// Serializes the finished entries as a sequence of `(key, value)` pairs, like `export()`.
+///
+/// Deserialize a `Vec<(K, V)>` to read them back, and pass it to a `from_entries_*()`
+/// constructor.  This is only available with the `serde` feature.
+#[cfg(feature = "serde")]
+impl<'a, K, V> serde::Serialize for Memoizer<'a, K, V>
+where
+    K: 'a + Clone + Debug + serde::Serialize,
+    V: 'a + Clone + Debug + serde::Serialize,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        // Some formats need the length up front, and `finished()` can't tell it.
+        let mut seq = serializer.serialize_seq(Some(self.len_finished()))?;
+        for entry in self.finished() {
+            seq.serialize_element(&entry)?;
+        }
+        seq.end()
+    }
+}
+
+/// Consumes the Memoizer, yielding its finished entries in the same order as `iter()`.
+impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> IntoIterator for Memoizer<'a, K, V> {
+    type Item = (K, V);
+    type IntoIter = Box<dyn 'a + Iterator<Item = (K, V)>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.cache.into_entries().filter_map(|(k, e)| match e.val {
+            MemoVal::Finished(v) => Some((k, v)),
+            _ => None,
+        }))
+    }
+}
+
+/// A deferred lookup of one key in a Memoizer.  See `Memoizer::lookup_lazy()`.
+pub struct Thunk<'m, 'a, K: 'a, V: 'a + Clone + Debug> {
+    key: K,
+    memo: &'m mut Memoizer<'a, K, V>,
+}
+
+impl<'m, 'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> Debug for Thunk<'m, 'a, K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Thunk {{ key: {:?} }}", self.key)
+    }
+}
+
+impl<'m, 'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> Thunk<'m, 'a, K, V> {
+    /// The key this thunk will look up.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+    /// Looks up the key, calculating a value if necessary, and releases the Memoizer.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `Memoizer::lookup()`.
+    pub fn force(self) -> V {
+        self.memo.lookup(&self.key)
+    }
+}
+
+// Quotes a CSV field if it needs it.
+fn csv_field(s: String) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
+
+impl<'a, V: 'a + Clone + Debug> Memoizer<'a, (usize, usize), V> {
+    /// Creates a Memoizer based on HashMap, keyed by windows into a borrowed buffer.
+    ///
+    /// Keys are `(start, len)` pairs.  The user function receives the window
+    /// `&buffer[start..start + len]` along with its key, so substring and subsequence problems
+    /// can be memoized without allocating an owned key for every window.
+    ///
+    /// The buffer is borrowed for as long as the Memoizer lives, so it cannot be modified while
+    /// cached values depend on its contents.  Looking up a window that does not fit inside the
+    /// buffer panics.
+    pub fn new_hash_slice_keyed<T, F>(buffer: &'a [T], user: F) -> Self
+    where
+        F: 'a + Fn(&mut Memoizer<(usize, usize), V>, &(usize, usize), &'a [T]) -> V,
+    {
+        Memoizer::new_hash(
+            move |mem: &mut Memoizer<(usize, usize), V>, k: &(usize, usize)| {
+                let (start, len) = *k;
+                user(mem, k, &buffer[start..start + len])
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    fn fibonacci(mem: &mut Memoizer<usize, usize>, k: &usize) -> usize
+    {
+        let k = *k;
+        if k < 2 {
             k
         } else {
-            mem.lookup(&(k - 1)) + mem.lookup(&(k - 2))
+            mem.lookup(&(k - 1)) + mem.lookup(&(k - 2))
+        }
+    }
+
+    #[test]
+    fn fibs_ord() {
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        assert_eq!(fib_cache.lookup(&0), 0);
+        assert_eq!(fib_cache.lookup(&1), 1);
+        assert_eq!(fib_cache.lookup(&2), 1);
+        assert_eq!(fib_cache.lookup(&3), 2);
+        assert_eq!(fib_cache.lookup(&20), 6765);
+        assert_eq!(fib_cache.lookup(&30), 832040);
+        assert_eq!(fib_cache.lookup(&40), 102334155);
+    }
+    #[test]
+    fn fibs_hash() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        assert_eq!(fib_cache.lookup(&0), 0);
+        assert_eq!(fib_cache.lookup(&1), 1);
+        assert_eq!(fib_cache.lookup(&2), 1);
+        assert_eq!(fib_cache.lookup(&3), 2);
+        assert_eq!(fib_cache.lookup(&20), 6765);
+        assert_eq!(fib_cache.lookup(&30), 832040);
+        assert_eq!(fib_cache.lookup(&40), 102334155);
+    }
+    #[test]
+    fn retain_top_k_keeps_highest_scores() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&20);
+        assert_eq!(fib_cache.retain_top_k(3, |_, v| *v as f64), 18);
+        assert_eq!(fib_cache.lookup_immut(&20), Some(6765));
+        assert_eq!(fib_cache.lookup_immut(&19), Some(4181));
+        assert_eq!(fib_cache.lookup_immut(&18), Some(2584));
+        assert_eq!(fib_cache.lookup_immut(&17), None);
+        assert_eq!(fib_cache.lookup_immut(&0), None);
+    }
+    #[test]
+    fn pinned_entries_survive_retain_top_k() {
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        fib_cache.lookup(&20);
+        assert!(fib_cache.pin(&1));
+        assert!(!fib_cache.pin(&21));
+        fib_cache.retain_top_k(1, |_, v| *v as f64);
+        assert_eq!(fib_cache.lookup_immut(&20), Some(6765));
+        assert_eq!(fib_cache.lookup_immut(&1), Some(1));
+        assert_eq!(fib_cache.lookup_immut(&19), None);
+        assert!(fib_cache.unpin(&1));
+        fib_cache.retain_top_k(1, |_, v| *v as f64);
+        assert_eq!(fib_cache.lookup_immut(&1), None);
+    }
+    #[test]
+    fn invalidate_and_recompute_follows_dependents() {
+        use std::cell::Cell;
+        let base = Cell::new(1);
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| match *k {
+            0 => base.get(),
+            k if k % 2 == 0 => mem.lookup(&(k - 2)) + 1,
+            _ => 100,
+        });
+        mem.set_track_dependencies(true);
+        assert_eq!(mem.lookup(&6), 4);
+        assert_eq!(mem.lookup(&3), 100);
+        base.set(10);
+        mem.invalidate_and_recompute(&0);
+        assert_eq!(mem.lookup_immut(&0), Some(10));
+        assert_eq!(mem.lookup_immut(&4), Some(12));
+        assert_eq!(mem.lookup_immut(&6), Some(13));
+        assert_eq!(mem.lookup_immut(&3), Some(100));
+    }
+    #[test]
+    fn critical_path_follows_longest_chain() {
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        assert_eq!(fib_cache.critical_path(), 0);
+        fib_cache.lookup(&3);
+        assert_eq!(fib_cache.critical_path(), 1);
+        fib_cache.set_track_dependencies(true);
+        fib_cache.lookup(&10);
+        assert_eq!(
+            fib_cache.critical_path_keys(),
+            vec![10, 9, 8, 7, 6, 5, 4, 3]
+        );
+        fib_cache.invalidate_and_recompute(&3);
+        assert_eq!(fib_cache.critical_path(), 9);
+    }
+    #[test]
+    fn find_cycle_after_recurrence_changes() {
+        use std::cell::Cell;
+        let flipped = Cell::new(false);
+        let mut mem =
+            Memoizer::new_ord(
+                |mem: &mut Memoizer<u64, u64>, k: &u64| match (*k, flipped.get()) {
+                    (1, false) => mem.lookup(&2) + 1,
+                    (2, true) => mem.lookup(&1) + 1,
+                    (3, _) => mem.lookup(&1),
+                    _ => 0,
+                },
+            );
+        mem.set_track_dependencies(true);
+        mem.lookup(&3);
+        assert!(!mem.has_cycle());
+        mem.retain_top_k(2, |k, _| if *k == 2 { 0.0 } else { 1.0 });
+        flipped.set(true);
+        mem.lookup(&2);
+        assert!(mem.has_cycle());
+        let cycle = mem.find_cycle().unwrap();
+        assert!(cycle == vec![1, 2] || cycle == vec![2, 1]);
+    }
+    #[test]
+    fn warm_computes_bottom_up() {
+        use std::cell::Cell;
+        let calls = Cell::new(0);
+        let mut mem = Memoizer::new_ord(|mem: &mut Memoizer<u64, u64>, k: &u64| {
+            calls.set(calls.get() + 1);
+            if *k == 0 {
+                0
+            } else {
+                mem.lookup(&(k - 1)) + k
+            }
+        });
+        let deps = |k: &u64| if *k == 0 { vec![] } else { vec![k - 1] };
+        assert_eq!(mem.warm(&[100_000], deps), Ok(()));
+        assert_eq!(calls.get(), 100_001);
+        assert_eq!(mem.lookup(&100_000), 5_000_050_000);
+        assert_eq!(calls.get(), 100_001);
+    }
+    #[test]
+    fn warm_detects_cycles() {
+        let mut mem = Memoizer::new_hash(|_: &mut Memoizer<u64, u64>, k: &u64| *k);
+        let result = mem.warm(&[0], |k| vec![(k + 1) % 3]);
+        assert_eq!(result, Err(CycleError { key: 0 }));
+        assert_eq!(mem.lookup_immut(&1), None);
+    }
+    #[test]
+    fn to_sorted_map_from_hash() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&5);
+        let sorted: Vec<(usize, usize)> = fib_cache.to_sorted_map().into_iter().collect();
+        assert_eq!(sorted, vec![(0, 0), (1, 1), (2, 1), (3, 2), (4, 3), (5, 5)]);
+    }
+    #[test]
+    fn split_off_partitions_at_key() {
+        let mut lower = Memoizer::new_ord(fibonacci);
+        lower.lookup(&10);
+        let mut upper = lower.split_off(&5);
+        assert_eq!(lower.lookup_immut(&4), Some(3));
+        assert_eq!(lower.lookup_immut(&5), None);
+        assert_eq!(upper.lookup_immut(&5), Some(5));
+        assert_eq!(upper.lookup_immut(&4), None);
+        assert_eq!(upper.lookup(&12), 144);
+    }
+    #[test]
+    fn compute_cold_leaves_cache_alone() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&3);
+        assert_eq!(fib_cache.compute_cold(&20), 6765);
+        assert_eq!(fib_cache.lookup_immut(&3), Some(2));
+        assert_eq!(fib_cache.lookup_immut(&4), None);
+    }
+    #[test]
+    fn dedup_values_shares_rcs() {
+        let mut mem = Memoizer::new_ord(|_: &mut Memoizer<u64, Rc<String>>, k: &u64| {
+            Rc::new(format!("{}", k % 2))
+        });
+        let a = mem.lookup(&2);
+        let b = mem.lookup(&4);
+        let odd = mem.lookup(&3);
+        assert!(!Rc::ptr_eq(&a, &b));
+        drop((a, b, odd));
+        mem.dedup_values();
+        let (a, b, odd) = (mem.lookup(&2), mem.lookup(&4), mem.lookup(&3));
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &odd));
+        assert_eq!(*odd, "1");
+    }
+    #[test]
+    fn lookup_all_new_skips_cached_keys() {
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        fib_cache.lookup(&5);
+        let new = fib_cache.lookup_all_new(&[3, 7, 6, 7, 9]);
+        assert_eq!(new, vec![(7, 13), (9, 34)]);
+    }
+    #[test]
+    fn as_fn_mut_in_map() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        let fibs: Vec<usize> = [10, 20, 30].iter().map(fib_cache.as_fn_mut()).collect();
+        assert_eq!(fibs, vec![55, 6765, 832040]);
+        assert_eq!(fib_cache.lookup_immut(&29), Some(514229));
+    }
+    #[test]
+    fn histogram_by_parity() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&10);
+        let counts = fib_cache.histogram(|k| k % 2 == 0);
+        assert_eq!(counts.get(&true), Some(&6));
+        assert_eq!(counts.get(&false), Some(&5));
+    }
+    fn sum_below(mem: &mut Memoizer<usize, usize>, k: &usize) -> usize {
+        (0..*k).map(|j| mem.lookup(&j)).sum::<usize>() + 1
+    }
+
+    fn count_down(mem: &mut Memoizer<usize, usize>, k: &usize) -> usize {
+        if *k == 0 {
+            0
+        } else {
+            mem.lookup(&(k - 1)) + 1
         }
     }
 
+    fn in_progress_count(mem: &Memoizer<usize, usize>) -> usize {
+        mem.cache
+            .iter()
+            .filter(|(_, e)| e.val == MemoVal::InProgress)
+            .count()
+    }
+
     #[test]
-    fn fibs_ord() {
+    fn in_progress_keys_in_call_order() {
+        let seen = RefCell::new(Vec::new());
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| {
+            if *k == 0 {
+                *seen.borrow_mut() = mem.in_progress_keys_snapshot();
+                0
+            } else {
+                mem.lookup(&(k - 1)) + 1
+            }
+        });
+        mem.lookup(&3);
+        assert_eq!(*seen.borrow(), vec![3, 2, 1, 0]);
+        assert!(mem.in_progress_keys_snapshot().is_empty());
+    }
+
+    #[test]
+    fn fanout_exceeded_cleans_up() {
+        let mut mem = Memoizer::new_hash(sum_below);
+        mem.set_max_fanout(3);
+        let err = mem.try_lookup(&4).unwrap_err();
+        assert_eq!(
+            err,
+            LookupError::FanoutExceeded {
+                key: 4,
+                max_fanout: 3
+            }
+        );
+        assert_eq!(in_progress_count(&mem), 0);
+        assert_eq!(mem.lookup_immut(&2), Some(4));
+        assert_eq!(mem.lookup_immut(&3), None);
+        assert_eq!(mem.try_lookup(&3), Ok(8));
+    }
+    #[test]
+    #[should_panic(expected = "Memoizer: calculation of key 5 made more than 2 lookups")]
+    fn fanout_exceeded_panics_in_lookup() {
+        let mut mem = Memoizer::new_ord(sum_below);
+        mem.set_max_fanout(2);
+        mem.lookup(&5);
+    }
+    #[test]
+    fn cancellation_stops_lookup() {
+        let cancel = AtomicBool::new(false);
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| {
+            if *k == 10 {
+                cancel.store(true, Ordering::Relaxed);
+            }
+            if *k == 0 {
+                0
+            } else {
+                mem.lookup(&(k - 1)) + 1
+            }
+        });
+        assert_eq!(mem.try_lookup_cancellable(&20, &cancel), Err(Cancelled));
+        assert_eq!(in_progress_count(&mem), 0);
+        assert_eq!(mem.lookup_immut(&10), None);
+        cancel.store(false, Ordering::Relaxed);
+        assert_eq!(mem.try_lookup_cancellable(&5, &cancel), Ok(5));
+        assert_eq!(mem.lookup(&20), 20);
+    }
+    #[test]
+    fn cancel_flag_set_from_another_thread() {
+        let cancel = AtomicBool::new(false);
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        std::thread::scope(|s| {
+            s.spawn(|| cancel.store(true, Ordering::Relaxed));
+        });
+        assert_eq!(
+            fib_cache.try_lookup_cancellable(&20, &cancel),
+            Err(Cancelled)
+        );
+        cancel.store(false, Ordering::Relaxed);
+        assert_eq!(fib_cache.try_lookup_cancellable(&20, &cancel), Ok(6765));
+    }
+    #[test]
+    fn user_panic_cleans_up() {
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| {
+            if *k == 0 {
+                panic!("base case");
+            }
+            mem.lookup(&(k - 1))
+        });
+        let result = panic::catch_unwind(AssertUnwindSafe(|| mem.lookup(&3)));
+        assert!(result.is_err());
+        assert_eq!(in_progress_count(&mem), 0);
+    }
+    #[test]
+    fn swallowed_abandonment_still_reaches_try_lookup() {
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| {
+            match *k {
+                1 => {
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| mem.lookup(&2)));
+                    1
+                }
+                2 => mem.lookup(&1),
+                k => k,
+            }
+        });
+        assert_eq!(
+            mem.try_lookup(&1),
+            Err(LookupError::Cycle(CycleError { key: 1 }))
+        );
+        assert_eq!(in_progress_count(&mem), 0);
+        assert_eq!(mem.lookup_immut(&1), None);
+        assert_eq!(mem.try_lookup(&3), Ok(3));
+    }
+    #[test]
+    fn swallowed_user_panic_unwinds_nested_frames() {
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| {
+            if *k == 0 {
+                panic!("base case");
+            }
+            panic::catch_unwind(AssertUnwindSafe(|| mem.lookup(&(k - 1)))).unwrap_or(*k)
+        });
+        assert_eq!(mem.lookup(&3), 1);
+        assert_eq!(in_progress_count(&mem), 0);
+        assert_eq!(mem.lookup_immut(&2), Some(1));
+        assert_eq!(mem.lookup_immut(&0), None);
+    }
+    #[test]
+    fn slice_keyed_palindromic_subsequence() {
+        let text = b"character";
+        let mut lps = Memoizer::new_hash_slice_keyed(
+            text,
+            |mem: &mut Memoizer<(usize, usize), usize>, &(start, len), window: &[u8]| {
+                if len < 2 {
+                    len
+                } else if window[0] == window[len - 1] {
+                    mem.lookup(&(start + 1, len - 2)) + 2
+                } else {
+                    mem.lookup(&(start + 1, len - 1))
+                        .max(mem.lookup(&(start, len - 1)))
+                }
+            },
+        );
+        assert_eq!(lps.lookup(&(0, text.len())), 5);
+        assert_eq!(lps.lookup_immut(&(2, 5)), Some(3));
+    }
+    #[test]
+    fn numeric_aggregates() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        assert_eq!(fib_cache.sum(), None);
+        assert_eq!(fib_cache.max(), None);
+        fib_cache.lookup(&10);
+        assert_eq!(fib_cache.sum(), Some(143));
+        assert_eq!(fib_cache.min(), Some(0));
+        assert_eq!(fib_cache.max(), Some(55));
+    }
+    #[test]
+    fn caching_disabled_recomputes() {
+        use std::cell::Cell;
+        let calls = Cell::new(0);
+        let mut mem = Memoizer::new_ord(|mem: &mut Memoizer<usize, usize>, k: &usize| {
+            calls.set(calls.get() + 1);
+            fibonacci(mem, k)
+        });
+        mem.lookup(&1);
+        mem.set_caching_enabled(false);
+        assert_eq!(mem.lookup(&10), 55);
+        assert_eq!(calls.get(), 178);
+        assert_eq!(mem.lookup_immut(&2), None);
+        mem.set_caching_enabled(true);
+        calls.set(0);
+        assert_eq!(mem.lookup(&10), 55);
+        assert_eq!(calls.get(), 10);
+    }
+    #[test]
+    #[should_panic(expected = "Memoizer: circular dependency on key 7")]
+    fn caching_disabled_detects_cycles() {
+        let mut mem =
+            Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| mem.lookup(k));
+        mem.set_caching_enabled(false);
+        mem.lookup(&7);
+    }
+    #[test]
+    fn with_stats_measures_scope() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&5);
+        let (v, stats) = fib_cache.with_stats(|mem| mem.lookup(&7));
+        assert_eq!(v, 13);
+        assert_eq!(
+            stats,
+            CacheStats {
+                hits: 3,
+                misses: 2,
+                top_level_misses: 1,
+                recursive_hits: 3,
+                recursive_misses: 1,
+                computations: 2,
+                ..CacheStats::default()
+            }
+        );
+        assert_eq!(fib_cache.stats().misses, 8);
+    }
+    #[test]
+    fn checkpoint_round_trip() {
+        let path = std::env::temp_dir().join(format!("red_memo_{}.checkpoint", std::process::id()));
         let mut fib_cache = Memoizer::new_ord(fibonacci);
-        assert_eq!(fib_cache.lookup(&0), 0);
-        assert_eq!(fib_cache.lookup(&1), 1);
-        assert_eq!(fib_cache.lookup(&2), 1);
-        assert_eq!(fib_cache.lookup(&3), 2);
-        assert_eq!(fib_cache.lookup(&20), 6765);
-        assert_eq!(fib_cache.lookup(&30), 832040);
-        assert_eq!(fib_cache.lookup(&40), 102334155);
+        fib_cache.lookup(&30);
+        fib_cache.checkpoint_to_path(&path).unwrap();
+        let mut loaded = Memoizer::new_hash(|_: &mut Memoizer<usize, usize>, _: &usize| 0);
+        assert_eq!(loaded.load_from_path(&path).unwrap(), 31);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.lookup(&30), 832040);
     }
     #[test]
-    fn fibs_hash() {
+    fn checkpoint_rejects_tabs_and_keeps_old_file() {
+        let dir = std::env::temp_dir().join(format!("red_memo_{}_tabs", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("entries");
+        let mut mem = Memoizer::new_ord(|_: &mut Memoizer<u32, String>, k: &u32| match *k {
+            1 => "one".to_string(),
+            k => format!("{}\t{}", k, k),
+        });
+        mem.lookup(&1);
+        mem.checkpoint_to_path(&path).unwrap();
+        mem.lookup(&2);
+        let err = mem.checkpoint_to_path(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\tone\n");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn store_into_outer_in_progress_key() {
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<u64, u64>, k: &u64| match *k {
+            2 => mem.lookup(&1) + 10,
+            1 => {
+                assert_eq!(mem.store(2, 99), None);
+                assert_eq!(mem.lookup(&2), 99);
+                1
+            }
+            _ => 0,
+        });
+        assert_eq!(mem.lookup(&2), 11);
+        assert_eq!(mem.lookup_immut(&2), Some(11));
+        assert_eq!(mem.store(2, 5), Some(11));
+    }
+    #[test]
+    fn store_into_abandoned_key_is_removed() {
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<u64, u64>, k: &u64| match *k {
+            100 => {
+                mem.store(100, 7);
+                mem.lookup(&101)
+            }
+            101 => mem.lookup(&101),
+            k => k,
+        });
+        assert_eq!(
+            mem.try_lookup(&100),
+            Err(LookupError::Cycle(CycleError { key: 101 }))
+        );
+        assert_eq!(mem.lookup_immut(&100), None);
+        assert!(mem.is_empty());
+        mem.set_capacity(2);
+        for k in 0..10 {
+            mem.lookup(&k);
+        }
+        assert_eq!(mem.len(), 2);
+    }
+    #[test]
+    fn stored_base_cases_end_recursion() {
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<u64, u64>, k: &u64| {
+            assert!(*k >= 2, "user function called for base case {}", k);
+            mem.lookup(&(k - 1)) + mem.lookup(&(k - 2))
+        });
+        assert_eq!(mem.store(0, 0), None);
+        assert_eq!(mem.store(1, 1), None);
+        assert_eq!(mem.lookup(&60), 1548008755920);
+        assert_eq!(mem.stats().misses, 59);
+    }
+    #[test]
+    fn lookup_lazy_defers_until_forced() {
         let mut fib_cache = Memoizer::new_hash(fibonacci);
-        assert_eq!(fib_cache.lookup(&0), 0);
-        assert_eq!(fib_cache.lookup(&1), 1);
-        assert_eq!(fib_cache.lookup(&2), 1);
-        assert_eq!(fib_cache.lookup(&3), 2);
+        assert_eq!(*fib_cache.lookup_lazy(10).key(), 10);
+        assert_eq!(fib_cache.len_finished(), 0);
+        assert_eq!(fib_cache.lookup_lazy(10).force(), 55);
+        assert_eq!(fib_cache.lookup_immut(&9), Some(34));
+    }
+    #[test]
+    fn capacity_evicts_least_recently_used() {
+        use std::cell::Cell;
+        let calls = Cell::new(0);
+        let mut mem = Memoizer::new_hash(|_: &mut Memoizer<u64, u64>, k: &u64| {
+            calls.set(calls.get() + 1);
+            k * 10
+        });
+        mem.set_capacity(2);
+        for k in 1..=3 {
+            mem.lookup(&k);
+        }
+        assert_eq!(calls.get(), 3);
+        assert_eq!(mem.lookup(&2), 20);
+        assert_eq!(mem.lookup(&3), 30);
+        assert_eq!(calls.get(), 3);
+        assert_eq!(mem.lookup(&1), 10);
+        assert_eq!(calls.get(), 4);
+        assert_eq!(mem.lookup_immut(&2), None);
+        assert_eq!(mem.stats().evictions, 2);
+    }
+    #[test]
+    fn capacity_spares_in_progress_and_pinned() {
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        fib_cache.lookup(&5);
+        fib_cache.pin(&0);
+        fib_cache.set_capacity(3);
+        assert_eq!(fib_cache.len_finished(), 3);
+        assert_eq!(fib_cache.lookup_immut(&0), Some(0));
         assert_eq!(fib_cache.lookup(&20), 6765);
+        assert_eq!(fib_cache.len_finished(), 3);
+        assert_eq!(fib_cache.lookup_immut(&20), Some(6765));
+        assert_eq!(fib_cache.lookup_immut(&0), Some(0));
+    }
+    #[test]
+    fn capacity_thrashing_counts_re_computations() {
+        let mut mem = Memoizer::new_hash(|_: &mut Memoizer<u64, u64>, k: &u64| k * 10);
+        mem.set_capacity(1);
+        for i in 0..10 {
+            mem.lookup(&(1 + i % 2));
+        }
+        let stats = mem.stats();
+        assert_eq!(stats.misses, 10);
+        assert_eq!(stats.evictions, 9);
+        assert_eq!(stats.re_computations, 8);
+        assert!(mem.is_known(&1));
+        assert_eq!(mem.lookup_immut(&1), None);
+        assert_eq!(mem.len(), 1);
+    }
+    #[test]
+    fn touch_protects_from_eviction() {
+        let mut mem = Memoizer::new_hash(|_: &mut Memoizer<u64, u64>, k: &u64| k * 10);
+        mem.set_capacity(2);
+        mem.lookup(&1);
+        mem.lookup(&2);
+        assert_eq!(mem.next_victim(), Some(&1));
+        assert!(mem.touch(&1));
+        assert!(!mem.touch(&3));
+        assert_eq!(mem.next_victim(), Some(&2));
+        mem.lookup(&3);
+        assert_eq!(mem.lookup_immut(&1), Some(10));
+        assert_eq!(mem.lookup_immut(&2), None);
+    }
+    #[test]
+    fn next_victim_skips_pinned() {
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        fib_cache.lookup(&5);
+        assert_eq!(fib_cache.next_victim(), None);
+        fib_cache.set_capacity(10);
+        assert_eq!(fib_cache.next_victim(), Some(&1));
+        fib_cache.pin(&1);
+        assert_eq!(fib_cache.next_victim(), Some(&0));
+        fib_cache.lookup(&0);
+        assert_eq!(fib_cache.next_victim(), Some(&2));
+        assert_eq!(fib_cache.len_finished(), 6);
+    }
+    #[test]
+    fn eviction_policy_switches_lru_on_and_off() {
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        assert_eq!(fib_cache.eviction_policy(), EvictionPolicy::None);
+        fib_cache.set_eviction_policy(EvictionPolicy::Lru(5)).unwrap();
+        assert_eq!(fib_cache.eviction_policy(), EvictionPolicy::Lru(5));
+        fib_cache.lookup(&20);
+        assert_eq!(fib_cache.len_finished(), 5);
+        fib_cache.set_eviction_policy(EvictionPolicy::None).unwrap();
+        assert_eq!(fib_cache.next_victim(), None);
+        // 16 through 20 are still cached, so only 21 through 30 are added.
+        fib_cache.lookup(&30);
+        assert_eq!(fib_cache.len_finished(), 15);
+        assert_eq!(fib_cache.stats().evictions, 16);
+    }
+    #[test]
+    fn lfu_keeps_frequently_used_entries() {
+        let mut mem = MemoizerBuilder::new()
+            .eviction_policy(EvictionPolicy::Lfu(2))
+            .build_hash(|_: &mut Memoizer<u64, u64>, k: &u64| k * 10)
+            .unwrap();
+        for _ in 0..3 {
+            mem.lookup(&1);
+        }
+        mem.lookup(&2);
+        assert_eq!(mem.next_victim(), Some(&2));
+        mem.lookup(&3);
+        assert_eq!(mem.lookup_immut(&1), Some(10));
+        assert_eq!(mem.lookup_immut(&2), None);
+        assert!(mem.touch(&3));
+        assert_eq!(mem.next_victim(), Some(&3));
+    }
+    #[test]
+    fn fifo_ignores_hits() {
+        let mut mem = MemoizerBuilder::new()
+            .eviction_policy(EvictionPolicy::Fifo(2))
+            .build_ord(|_: &mut Memoizer<u64, u64>, k: &u64| k * 10)
+            .unwrap();
+        mem.lookup(&1);
+        mem.lookup(&2);
+        mem.lookup(&1);
+        assert!(mem.touch(&1));
+        mem.lookup(&3);
+        assert_eq!(mem.lookup_immut(&1), None);
+        assert_eq!(mem.lookup_immut(&2), Some(20));
+        assert_eq!(mem.stats().evictions, 1);
+    }
+    #[test]
+    fn byte_bounded_evicts_by_size() {
+        let mut mem = MemoizerBuilder::new()
+            .eviction_policy(EvictionPolicy::ByteBounded(100))
+            .size_function(|v: &Vec<u8>| v.len())
+            .build_hash(|_: &mut Memoizer<usize, Vec<u8>>, k: &usize| vec![0; *k])
+            .unwrap();
+        mem.lookup(&60);
+        mem.lookup(&30);
+        assert_eq!(mem.len_finished(), 2);
+        mem.lookup(&20);
+        assert!(!mem.is_finished(&60));
+        mem.lookup(&60);
+        assert!(!mem.is_finished(&30));
+        assert!(mem.is_finished(&20) && mem.is_finished(&60));
+        assert_eq!(mem.stats().evictions, 2);
+        mem.set_size_function(|v: &Vec<u8>| v.len() + 30);
+        assert_eq!(mem.len_finished(), 1);
+        assert!(mem.is_finished(&60));
+    }
+    #[test]
+    fn eviction_policy_needs_matching_size_function() {
+        let user = |_: &mut Memoizer<usize, Vec<u8>>, k: &usize| vec![0; *k];
+        let measured = MemoizerBuilder::new()
+            .eviction_policy(EvictionPolicy::Lru(10))
+            .size_function(|v: &Vec<u8>| v.len())
+            .build_hash(user);
+        assert_eq!(measured.unwrap_err(), PolicyError::UnusedSizeFunction);
+        let unmeasured = MemoizerBuilder::new()
+            .eviction_policy(EvictionPolicy::ByteBounded(10))
+            .build_ord(user);
+        assert_eq!(unmeasured.unwrap_err(), PolicyError::MissingSizeFunction);
+        let mut mem = MemoizerBuilder::new().build_hash(user).unwrap();
+        assert_eq!(
+            mem.set_eviction_policy(EvictionPolicy::ByteBounded(10)),
+            Err(PolicyError::MissingSizeFunction)
+        );
+        assert_eq!(mem.eviction_policy(), EvictionPolicy::None);
+        mem.set_size_function(|v: &Vec<u8>| v.len());
+        assert_eq!(mem.set_eviction_policy(EvictionPolicy::ByteBounded(10)), Ok(()));
+    }
+    #[test]
+    fn overflow_tier_catches_evictions() {
+        use std::cell::Cell;
+        let calls = Cell::new(0);
+        let user = |_: &mut Memoizer<u64, u64>, k: &u64| {
+            calls.set(calls.get() + 1);
+            k * 10
+        };
+        let mut mem = Memoizer::new_hash(user);
+        mem.set_capacity(2);
+        mem.set_overflow_tier(Memoizer::new_hash(user));
+        for k in 1..=3 {
+            mem.lookup(&k);
+        }
+        let secondary = mem.overflow_tier().unwrap();
+        assert_eq!(secondary.lookup_immut(&1), Some(10));
+        assert_eq!(mem.lookup(&1), 10);
+        assert_eq!(calls.get(), 3);
+        let secondary = mem.overflow_tier().unwrap();
+        assert_eq!(secondary.lookup_immut(&1), None);
+        assert_eq!(secondary.lookup_immut(&2), Some(20));
+        assert_eq!(mem.lookup_immut(&1), Some(10));
+        assert_eq!(mem.stats().evictions, 2);
+    }
+    #[test]
+    fn invalidate_and_clear() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&10);
+        assert_eq!(fib_cache.len(), 11);
+        assert_eq!(fib_cache.invalidate(&5), Some(5));
+        assert_eq!(fib_cache.invalidate(&5), None);
+        assert!(!fib_cache.contains_key(&5));
+        assert!(fib_cache.contains_key(&4) && fib_cache.contains_key(&6));
+        let (v, stats) = fib_cache.with_stats(|mem| mem.lookup(&5));
+        assert_eq!(v, 5);
+        assert_eq!((stats.misses, stats.hits), (1, 2));
+        fib_cache.clear();
+        assert_eq!(fib_cache.len(), 0);
+        assert!(fib_cache.is_empty());
+        assert_eq!(fib_cache.lookup(&10), 55);
+    }
+    #[test]
+    fn len_follows_every_removal() {
+        fn check(mem: &Memoizer<usize, usize>) {
+            assert_eq!(mem.len(), mem.iter().count());
+        }
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        fib_cache.lookup(&20);
+        check(&fib_cache);
+        fib_cache.set_capacity(15);
+        check(&fib_cache);
+        fib_cache.pin(&20);
+        fib_cache.clear_values_keep_keys();
+        check(&fib_cache);
+        fib_cache.lookup(&25);
+        fib_cache.retain(|k, _| k % 3 != 0);
+        check(&fib_cache);
+        let upper = fib_cache.split_off(&22);
+        check(&fib_cache);
+        check(&upper);
+        fib_cache.remove_many(&[1, 2, 20]);
+        check(&fib_cache);
+        assert_eq!(fib_cache.try_lookup_with(&30, |_, _| Err(())), Err(()));
+        check(&fib_cache);
+    }
+    #[test]
+    fn remove_many_in_input_order() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&10);
+        let removed = fib_cache.remove_many(&[7, 20, 3, 7]);
+        assert_eq!(removed, vec![Some(13), None, Some(2), None]);
+        assert_eq!(fib_cache.len_finished(), 9);
+        assert!(!fib_cache.contains_key(&3));
+    }
+    #[test]
+    fn invalidate_spares_in_progress_key() {
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<u64, u64>, k: &u64| {
+            if *k == 0 {
+                mem.clear();
+                assert_eq!(mem.invalidate(&1), None);
+                0
+            } else {
+                mem.lookup(&(k - 1)) + 1
+            }
+        });
+        assert_eq!(mem.try_lookup(&3), Ok(3));
+        assert_eq!(mem.len(), 4);
+    }
+    #[test]
+    fn verify_determinism_passes_pure_function() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.set_verify_determinism(true);
         assert_eq!(fib_cache.lookup(&30), 832040);
-        assert_eq!(fib_cache.lookup(&40), 102334155);
+        assert_eq!(fib_cache.stats().misses, 31);
+        // A chain has no hits of its own, so the hits are the recalculations of sampled keys,
+        // and with 301 keys the sample is never empty in practice.
+        let mut mem = Memoizer::new_hash(count_down);
+        mem.set_verify_determinism(true);
+        assert_eq!(mem.lookup(&300), 300);
+        assert_eq!(mem.stats().misses, 301);
+        if cfg!(debug_assertions) {
+            assert!(mem.stats().hits > 0);
+        }
+    }
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "user function is not deterministic")]
+    fn verify_determinism_catches_hidden_state() {
+        use std::cell::Cell;
+        let calls = Cell::new(0);
+        let mut mem = Memoizer::new_hash(|_: &mut Memoizer<u64, u64>, k: &u64| {
+            calls.set(calls.get() + 1);
+            k + calls.get()
+        });
+        mem.set_verify_determinism(true);
+        for k in 0..100 {
+            mem.lookup(&k);
+        }
+    }
+    #[test]
+    fn export_import_round_trip() {
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        fib_cache.lookup(&40);
+        let entries = fib_cache.export();
+        assert_eq!(entries.len(), 41);
+        let mut loaded = Memoizer::from_entries_hash(fibonacci, entries);
+        assert_eq!(loaded.lookup(&40), 102334155);
+        assert_eq!(loaded.stats().misses, 0);
+    }
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serialize_finished_entries() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&40);
+        let bytes = bincode::serialize(&fib_cache).unwrap();
+        let entries: Vec<(usize, usize)> = bincode::deserialize(&bytes).unwrap();
+        let mut loaded = Memoizer::from_entries_ord(fibonacci, entries);
+        assert_eq!(loaded.len(), 41);
+        assert_eq!(loaded.lookup(&40), 102334155);
+        assert_eq!(loaded.stats().hits, 1);
+        assert_eq!(loaded.stats().misses, 0);
+    }
+    #[test]
+    fn lookup_transient_leaves_no_entries() {
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| match *k {
+            100 => mem.lookup_transient(&20) + 1,
+            200 => mem.lookup_transient(&200),
+            k => fibonacci(mem, &k),
+        });
+        assert_eq!(mem.lookup(&100), 6766);
+        assert_eq!(mem.len_finished(), 1);
+        assert_eq!(mem.lookup_immut(&20), None);
+        assert_eq!(
+            mem.try_lookup(&200),
+            Err(LookupError::Cycle(CycleError { key: 200 }))
+        );
+        assert_eq!(mem.lookup(&5), 5);
+        assert_eq!(mem.len_finished(), 7);
+    }
+    #[test]
+    fn lookup_iterative_avoids_deep_recursion() {
+        // Far deeper than `lookup()` can recurse on a test thread's stack.
+        let mut mem = Memoizer::new_hash(
+            |mem: &mut Memoizer<u64, u64>, k: &u64| {
+                if *k == 0 {
+                    0
+                } else {
+                    mem.lookup(&(k - 1)) + k
+                }
+            },
+        );
+        let deps = |k: &u64| if *k == 0 { vec![] } else { vec![k - 1] };
+        let combine = |k: &u64, vs: &[u64]| vs.iter().sum::<u64>() + k;
+        assert_eq!(
+            mem.lookup_iterative(&200_000, deps, combine),
+            20_000_100_000
+        );
+        assert_eq!(mem.stats().misses, 200_001);
+        assert_eq!(mem.lookup(&200_001), 20_000_300_001);
+    }
+    #[test]
+    fn lookup_iterative_shares_diamonds() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&5);
+        let deps = |k: &usize| if *k < 2 { vec![] } else { vec![k - 1, k - 2] };
+        let combine = |k: &usize, vs: &[usize]| if *k < 2 { *k } else { vs[0] + vs[1] };
+        assert_eq!(
+            fib_cache.lookup_iterative(&90, deps, combine),
+            2880067194370816120
+        );
+        assert_eq!(fib_cache.stats().misses, 6 + 85);
+    }
+    #[test]
+    #[should_panic(expected = "Memoizer: circular dependency on key")]
+    fn lookup_iterative_detects_cycles() {
+        let mut mem = Memoizer::new_hash(|_: &mut Memoizer<u64, u64>, k: &u64| *k);
+        mem.lookup_iterative(&0, |k| vec![(k + 1) % 3], |_, vs| vs[0]);
+    }
+    #[test]
+    fn structured_recurrence() {
+        use std::cell::Cell;
+        let combined = Cell::new(0);
+        let mut mem = Memoizer::new_ord_structured(
+            |k: &u64| if *k == 0 { vec![] } else { vec![k - 1] },
+            |k, vs: &[u64]| {
+                combined.set(combined.get() + 1);
+                vs.first().map_or(0, |v| v + k)
+            },
+        );
+        assert_eq!(mem.lookup(&200_000), 20_000_100_000);
+        assert_eq!(combined.get(), 200_001);
+        assert_eq!(mem.lookup_immut(&1000), Some(500_500));
+        assert_eq!(mem.lookup(&200_001), 20_000_300_001);
+        assert_eq!(combined.get(), 200_002);
+    }
+    #[test]
+    fn lookup_with_seed_uses_seeded_values() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        let v = fib_cache.lookup_with_seed(&40, vec![(30, 832040), (31, 1346269)]);
+        assert_eq!(v, 102334155);
+        assert_eq!(fib_cache.stats().misses, 9);
+        assert_eq!(fib_cache.lookup_immut(&30), Some(832040));
+        assert_eq!(fib_cache.lookup_immut(&29), None);
+    }
+    #[test]
+    fn compute_until_first_large_fibonacci() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        let found = fib_cache.compute_until(0, |k| k + 1, |_, v| *v >= 1000);
+        assert_eq!(found, (17, 1597));
+        let (_, stats) =
+            fib_cache.with_stats(|mem| mem.compute_until(5, |k| k + 1, |_, v| *v > 100));
+        assert_eq!(stats.misses, 0);
+    }
+    #[test]
+    fn store_overwrites_finished_value() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        assert_eq!(fib_cache.lookup(&10), 55);
+        assert_eq!(fib_cache.store(10, 0), Some(55));
+        assert_eq!(fib_cache.lookup(&10), 0);
+        assert_eq!(fib_cache.store(10, 55), Some(0));
+        assert_eq!(fib_cache.store(11, 89), None);
+    }
+    #[test]
+    fn entries_since_marker() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&3);
+        let m = fib_cache.mark();
+        assert_eq!(fib_cache.entries_since(m), vec![]);
+        fib_cache.lookup(&5);
+        fib_cache.store(0, 0);
+        assert_eq!(fib_cache.entries_since(m), vec![(4, 3), (5, 5), (0, 0)]);
+        fib_cache.invalidate(&4);
+        fib_cache.set_capacity(5);
+        assert_eq!(fib_cache.entries_since(m), vec![(5, 5), (0, 0)]);
+        fib_cache.clear();
+        assert_eq!(fib_cache.entries_since(m), vec![]);
+    }
+    #[test]
+    fn clear_values_keep_keys() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&10);
+        fib_cache.clear_values_keep_keys();
+        assert!(fib_cache.is_known(&10));
+        assert!(!fib_cache.is_known(&11));
+        assert_eq!(fib_cache.lookup_immut(&10), None);
+        assert_eq!(fib_cache.len_finished(), 0);
+        assert_eq!(fib_cache.lookup(&5), 5);
+        assert_eq!(fib_cache.len_finished(), 6);
+        assert!(fib_cache.is_known(&9));
+        assert_eq!(fib_cache.stats().evictions, 11);
+        assert_eq!(fib_cache.stats().re_computations, 6);
+    }
+    #[test]
+    fn scratch_buffers_per_depth() {
+        // Number of distinct digits in k, counted with a scratch Vec, plus the same for k / 10.
+        let mut mem = Memoizer::new_hash_with_scratch(
+            |mem: &mut Memoizer<u64, usize>, k: &u64, digits: &mut Vec<u64>| {
+                assert!(digits.is_empty());
+                let mut n = *k;
+                while n > 0 {
+                    digits.push(n % 10);
+                    n /= 10;
+                }
+                digits.sort();
+                digits.dedup();
+                let here = digits.len();
+                if *k == 0 {
+                    here
+                } else {
+                    let rest = mem.lookup(&(k / 10));
+                    assert_eq!(digits.len(), here);
+                    here + rest
+                }
+            },
+        );
+        assert_eq!(mem.lookup(&1223), 3 + 2 + 2 + 1);
+    }
+    #[test]
+    fn stats_count_hits_misses_and_skips() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&5);
+        fib_cache.lookup(&5);
+        let expected = CacheStats {
+            hits: 4,
+            misses: 6,
+            top_level_hits: 1,
+            top_level_misses: 1,
+            recursive_hits: 3,
+            recursive_misses: 5,
+            computations: 6,
+            ..CacheStats::default()
+        };
+        assert_eq!(fib_cache.stats(), expected);
+        fib_cache.reset_stats();
+        assert_eq!(fib_cache.stats(), CacheStats::default());
+
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.set_memo_predicate(|k: &usize| *k >= 2);
+        fib_cache.lookup(&5);
+        let expected = CacheStats {
+            hits: 2,
+            misses: 7,
+            predicate_skips: 3,
+            top_level_misses: 1,
+            recursive_hits: 2,
+            recursive_misses: 6,
+            computations: 7,
+            ..CacheStats::default()
+        };
+        assert_eq!(fib_cache.stats(), expected);
+        fib_cache.lookup(&1);
+        assert_eq!(fib_cache.stats().misses, 8);
+        assert_eq!(fib_cache.stats().predicate_skips, 4);
+    }
+    #[test]
+    fn finished_keys_respect_predicate() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.set_memo_predicate(|k| k % 2 == 0);
+        fib_cache.lookup(&6);
+        assert_eq!(
+            fib_cache.finished_keys(),
+            vec![0, 2, 4, 6].into_iter().collect()
+        );
+    }
+    #[test]
+    fn lookup_with_fuel_resumes() {
+        let mut mem = Memoizer::new_hash(sum_below);
+        assert_eq!(mem.lookup_with_fuel(&30, 10), Err(OutOfFuel));
+        assert_eq!(in_progress_count(&mem), 0);
+        assert_eq!(mem.len_finished(), 9);
+        let mut calls = 1;
+        let v = loop {
+            calls += 1;
+            if let Ok(v) = mem.lookup_with_fuel(&30, 10) {
+                break v;
+            }
+        };
+        assert_eq!(v, 1 << 30);
+        assert_eq!(calls, 4);
+        assert_eq!(mem.lookup_with_fuel(&30, 0), Ok(1 << 30));
+    }
+    #[test]
+    fn write_csv_quotes_fields() {
+        let mut mem = Memoizer::new_ord(|_: &mut Memoizer<usize, String>, k: &usize| match *k {
+            0 => "plain".to_string(),
+            1 => "a, b".to_string(),
+            _ => "say \"hi\"".to_string(),
+        });
+        for k in 0..3 {
+            mem.lookup(&k);
+        }
+        let mut out = Vec::new();
+        mem.write_csv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "key,value\n0,plain\n1,\"a, b\"\n2,\"say \"\"hi\"\"\"\n"
+        );
+    }
+    #[test]
+    fn lookup_and_collect_new_entries() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&3);
+        let (v, new) = fib_cache.lookup_and_collect_new(&5);
+        assert_eq!(v, 5);
+        assert_eq!(new, vec![(4, 3), (5, 5)]);
+        assert_eq!(fib_cache.lookup_and_collect_new(&5), (5, vec![]));
+    }
+    #[test]
+    fn lookup_and_collect_new_skips_stored_and_keeps_evicted() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.set_read_through(|k| if *k == 2 { Some(1) } else { None }, |_, _| {});
+        fib_cache.set_capacity(3);
+        let (v, new) = fib_cache.lookup_and_collect_new(&5);
+        assert_eq!(v, 5);
+        assert_eq!(new, vec![(1, 1), (3, 2), (4, 3), (5, 5)]);
+        assert_eq!(fib_cache.lookup_immut(&1), None);
+    }
+    #[test]
+    fn lookup_ordered_stays_shallow() {
+        let depth = Rc::new(std::cell::Cell::new(0));
+        let d = Rc::clone(&depth);
+        let mut mem = Memoizer::new_hash(move |mem: &mut Memoizer<u64, u64>, k: &u64| {
+            d.set(d.get().max(mem.stack.len()));
+            if *k == 0 {
+                0
+            } else {
+                mem.lookup(&(k - 1)) + k
+            }
+        });
+        mem.set_dependency_order(|a: &u64, b: &u64| a.cmp(b));
+        assert_eq!(mem.lookup_ordered(&1000, (0..2000).rev()), 500500);
+        assert_eq!(depth.get(), 1);
+    }
+    #[test]
+    fn iter_where_sorted() {
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        fib_cache.lookup(&10);
+        let odd: Vec<(&usize, &usize)> = fib_cache.iter_where(|k| k % 2 == 1 && *k < 8).collect();
+        assert_eq!(odd, vec![(&1, &1), (&3, &2), (&5, &5), (&7, &13)]);
+    }
+    #[test]
+    fn try_lookup_with_caches_only_ok() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        let failed: Result<usize, &str> = fib_cache.try_lookup_with(&10, |mem, _| {
+            mem.lookup(&9);
+            Err("no")
+        });
+        assert_eq!(failed, Err("no"));
+        assert_eq!(in_progress_count(&fib_cache), 0);
+        assert_eq!(fib_cache.lookup_immut(&10), None);
+        assert_eq!(fib_cache.lookup_immut(&9), Some(34));
+        let ok: Result<usize, &str> =
+            fib_cache.try_lookup_with(&10, |mem, _| Ok(mem.lookup(&9) + mem.lookup(&8)));
+        assert_eq!(ok, Ok(55));
+        assert_eq!(fib_cache.lookup_immut(&10), Some(55));
+        assert_eq!(fib_cache.try_lookup_with(&10, |_, _| Err("unused")), Ok(55));
+    }
+    #[test]
+    fn stats_csv() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.set_memo_predicate(|k: &usize| *k >= 2);
+        assert_eq!(fib_cache.stats_csv_row(0), "0,0,0,0,0,0");
+        fib_cache.lookup(&5);
+        assert_eq!(
+            fib_cache.stats_csv_header(),
+            "timestamp,hits,misses,len,total_computations,max_depth"
+        );
+        assert_eq!(fib_cache.stats_csv_row(1700000000), "1700000000,2,7,4,7,5");
+
+        let mut mem = Memoizer::new_hash(count_down);
+        mem.set_verify_determinism(true);
+        mem.lookup(&300);
+        let row = mem.stats_csv_row(0);
+        let columns: Vec<u64> = row.split(',').map(|c| c.parse().unwrap()).collect();
+        assert_eq!(columns[2], 301);
+        if cfg!(debug_assertions) {
+            assert!(columns[4] > columns[2]);
+        }
+    }
+    #[test]
+    fn prometheus_format() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&5);
+        let text = fib_cache.prometheus_metrics("fib");
+        let samples: Vec<&str> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            samples,
+            vec![
+                "fib_hits_total 3",
+                "fib_misses_total 6",
+                "fib_computations_total 6",
+                "fib_predicate_skips_total 0",
+                "fib_evictions_total 0",
+                "fib_re_computations_total 0",
+                "fib_entries 6",
+                "fib_max_depth 5",
+            ]
+        );
+        assert!(text.starts_with("# HELP fib_hits_total "));
+        assert!(text.contains("# TYPE fib_entries gauge\n"));
+        assert!(text.contains("# HELP fib_computations_total "));
+        assert!(text.contains("# TYPE fib_computations_total counter\n"));
+    }
+    #[test]
+    fn state_hash_ignores_backend_order() {
+        let mut by_hash = Memoizer::new_hash(fibonacci);
+        let mut by_ord = Memoizer::new_ord(fibonacci);
+        assert_eq!(by_hash.state_hash(), by_ord.state_hash());
+        by_hash.lookup(&30);
+        by_ord.lookup(&30);
+        assert_eq!(by_hash.state_hash(), by_ord.state_hash());
+        by_ord.store(12, 0);
+        assert_ne!(by_hash.state_hash(), by_ord.state_hash());
+    }
+    #[test]
+    fn benchmark_caching_counts_avoided_computations() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        let benefit = fib_cache.benchmark_caching(&[15]);
+        assert_eq!(benefit.avoided_computations, 1973 - 16);
+        assert_eq!(fib_cache.len_finished(), 16);
+        // The timings depend on the machine, so only their ratio's sanity is checked.  The
+        // cached run calculates 16 values, which always takes measurable time.
+        assert!(benefit.speedup.is_finite() && benefit.speedup > 0.0);
+        let benefit = fib_cache.benchmark_caching(&[15, 10]);
+        assert_eq!(benefit.avoided_computations, 1973 + 177);
+        assert!(benefit.speedup > 0.0);
+        assert_eq!(
+            fib_cache.benchmark_caching(&[]),
+            CacheBenefit {
+                speedup: 1.0,
+                avoided_computations: 0,
+            }
+        );
+    }
+    #[test]
+    fn benchmark_caching_with_a_manual_clock() {
+        let now = Cell::new(Instant::now());
+        let mut fib_cache = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| {
+            now.set(now.get() + Duration::from_secs(1));
+            fibonacci(mem, k)
+        });
+        fib_cache.set_clock(|| now.get());
+        let benefit = fib_cache.benchmark_caching(&[15]);
+        assert_eq!(benefit.speedup, 1973.0 / 16.0);
+        assert_eq!(fib_cache.benchmark_caching(&[15]).speedup, f64::INFINITY);
+        let start = now.get();
+        fib_cache.lookup(&16);
+        assert_eq!(now.get() - start, Duration::from_secs(1));
+    }
+    #[test]
+    fn diff_two_caches() {
+        let mut old = Memoizer::new_ord(fibonacci);
+        old.lookup(&6);
+        let mut new = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| match *k {
+            0 => 0,
+            1 | 2 => 1,
+            k => mem.lookup(&(k - 1)) + mem.lookup(&(k - 2)) + (k == 5) as usize,
+        });
+        new.lookup(&7);
+        let diff = old.diff(&new);
+        assert_eq!(diff.only_self, vec![0]);
+        assert_eq!(diff.only_other, vec![7]);
+        assert_eq!(diff.differing, vec![(5, 5, 6), (6, 8, 9)]);
+        assert_eq!(old.diff(&old), CacheDiff::default());
+    }
+    #[test]
+    fn read_through_store() {
+        let disk = Rc::new(RefCell::new(HashMap::new()));
+        disk.borrow_mut().insert(19, 4181);
+        disk.borrow_mut().insert(20, 6765);
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        let (r, w) = (Rc::clone(&disk), Rc::clone(&disk));
+        fib_cache.set_read_through(
+            move |k| r.borrow().get(k).copied(),
+            move |k, v| {
+                w.borrow_mut().insert(*k, *v);
+            },
+        );
+        assert_eq!(fib_cache.lookup(&22), 17711);
+        assert_eq!(fib_cache.stats().misses, 2);
+        assert_eq!(disk.borrow().get(&22), Some(&17711));
+        assert_eq!(disk.borrow().len(), 4);
+    }
+    #[test]
+    fn writeback_in_batches() {
+        let disk = Rc::new(RefCell::new(HashMap::new()));
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        let (r, w) = (Rc::clone(&disk), Rc::clone(&disk));
+        fib_cache.set_read_through(
+            move |k| r.borrow().get(k).copied(),
+            move |k, v| {
+                w.borrow_mut().insert(*k, *v);
+            },
+        );
+        fib_cache.set_writeback_batch(4);
+        fib_cache.lookup(&5);
+        assert_eq!(disk.borrow().len(), 4);
+        assert_eq!(fib_cache.lookup_immut(&5), Some(5));
+        fib_cache.flush();
+        assert_eq!(disk.borrow().len(), 6);
+        fib_cache.lookup(&7);
+        assert_eq!(disk.borrow().len(), 6);
+        drop(fib_cache);
+        assert_eq!(disk.borrow().get(&7), Some(&13));
+    }
+    #[test]
+    fn retain_counts_removals() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&10);
+        fib_cache.pin(&9);
+        assert_eq!(fib_cache.retain(|k, _| k % 2 == 0), 4);
+        assert_eq!(
+            fib_cache.finished_keys(),
+            vec![0, 2, 4, 6, 8, 9, 10].into_iter().collect()
+        );
+        assert_eq!(fib_cache.retain(|_, _| true), 0);
+        assert_eq!(fib_cache.stats().evictions, 4);
+    }
+    #[test]
+    fn freeze_keeps_finished_entries() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&30);
+        let frozen = fib_cache.freeze();
+        assert_eq!(frozen.len(), 31);
+        assert_eq!(frozen.get(&30), Some(&832040));
+        assert_eq!(frozen.get(&31), None);
+        assert!(frozen.iter().map(|(k, _)| *k).eq(0..=30));
+    }
+    #[test]
+    fn write_entries_where_filters() {
+        let mut fib_cache = Memoizer::new_ord(fibonacci);
+        fib_cache.lookup(&10);
+        let mut out = Vec::new();
+        fib_cache
+            .write_entries_where(&mut out, |k, _| *k >= 8)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "8\t21\n9\t34\n10\t55\n");
+    }
+    #[test]
+    fn missing_dependencies_stop_at_finished() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&5);
+        let deps = |k: &usize| if *k < 2 { vec![] } else { vec![k - 1, k - 2] };
+        assert_eq!(fib_cache.missing_dependencies(&[8, 7], deps), vec![8, 7, 6]);
+        assert_eq!(
+            fib_cache.missing_dependencies(&[5], deps),
+            Vec::<usize>::new()
+        );
+    }
+    #[test]
+    fn map_into_new_representation() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&10);
+        let dest = Memoizer::new_ord(|mem: &mut Memoizer<u64, String>, k: &u64| {
+            let a: u64 = mem.lookup(&(k - 1)).parse().unwrap();
+            let b: u64 = mem.lookup(&(k - 2)).parse().unwrap();
+            (a + b).to_string()
+        });
+        let mut text = fib_cache.map_into(|k| *k as u64, |v| v.to_string(), dest);
+        assert_eq!(text.lookup_immut(&10), Some("55".to_string()));
+        assert_eq!(text.lookup(&12), "144");
+    }
+    #[test]
+    fn self_cycle_is_an_error() {
+        let mut mem =
+            Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| mem.lookup(k));
+        assert_eq!(
+            mem.try_lookup(&3),
+            Err(LookupError::Cycle(CycleError { key: 3 }))
+        );
+        assert_eq!(mem.cache.iter().count(), 0);
+    }
+    #[test]
+    fn mutual_cycle_leaves_cache_clean() {
+        // 5 and 6 call each other, unless 6 is reached from 7.
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| match *k {
+            5 => mem.lookup(&6) + 1,
+            6 if mem.stack.len() > 1 && mem.stack[0].key == 7 => 10,
+            6 => mem.lookup(&5) + 1,
+            7 => mem.lookup(&6) + mem.lookup(&1),
+            _ => 0,
+        });
+        mem.lookup(&1);
+        assert_eq!(
+            mem.try_lookup(&5),
+            Err(LookupError::Cycle(CycleError { key: 5 }))
+        );
+        assert_eq!(in_progress_count(&mem), 0);
+        assert_eq!(mem.finished_keys(), vec![1].into_iter().collect());
+        assert_eq!(mem.try_lookup(&7), Ok(10));
+    }
+    #[test]
+    #[should_panic(expected = "Memoizer: circular dependency on key 4")]
+    fn nested_cycle_panics_in_lookup() {
+        let mut mem = Memoizer::new_hash(|mem: &mut Memoizer<usize, usize>, k: &usize| match *k {
+            0 => mem.lookup(&4),
+            k => mem.lookup(&(k - 1)),
+        });
+        mem.lookup(&4);
+    }
+    #[test]
+    fn retain_reachable_from_roots() {
+        let mut fib_cache = Memoizer::new_hash(fibonacci);
+        fib_cache.lookup(&12);
+        let halves = |k: &usize| if *k > 1 { vec![k / 2] } else { vec![] };
+        assert_eq!(fib_cache.retain_reachable(&[12, 7], halves), 8);
+        assert_eq!(
+            fib_cache.finished_keys(),
+            vec![12, 6, 3, 1, 7].into_iter().collect()
+        );
+    }
+    #[test]
+    fn iter_skips_in_progress() {
+        let mut mem = Memoizer::new_ord(|mem: &mut Memoizer<usize, usize>, k: &usize| {
+            if *k == 0 {
+                return 0;
+            }
+            let v = mem.lookup(&(k - 1)) + k;
+            if *k == 3 {
+                assert_eq!(mem.iter().count(), 3);
+            }
+            v
+        });
+        mem.lookup(&5);
+        let argmax = mem.iter().max_by_key(|(_, v)| **v).map(|(k, _)| *k);
+        assert_eq!(argmax, Some(5));
+        let entries: Vec<(usize, usize)> = mem.into_iter().collect();
+        assert_eq!(
+            entries,
+            vec![(0, 0), (1, 1), (2, 3), (3, 6), (4, 10), (5, 15)]
+        );
     }
 }
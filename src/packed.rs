@@ -0,0 +1,340 @@
+//! A memoizer for dense `usize` keys that packs its bookkeeping into a bitset.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use crate::guard::guarded;
+use crate::MemoVal;
+
+// The state of a slot, two bits each.
+const ABSENT: u64 = 0;
+const IN_PROGRESS: u64 = 1;
+const FINISHED: u64 = 2;
+const SLOTS_PER_WORD: usize = 32;
+// The low bit of every slot in a word, which is set only for slots in progress.
+const IN_PROGRESS_BITS: u64 = 0x5555_5555_5555_5555;
+
+type PackedFunction<'a, V> = dyn 'a + Fn(&mut PackedMemoizer<V>, &usize) -> V;
+
+/// What a `PackedMemoizer` does with keys outside its range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DenseBounds {
+    /// Panic.  This is the default.
+    #[default]
+    Panic,
+    /// Extend the table so that it covers the key.
+    Grow,
+    /// Cache the key in a `HashMap` on the side, which suits a few sparse keys beyond the
+    /// dense range.
+    Fallback,
+}
+
+/// Memoization cache for a recursive user function over the keys `0..n`, with two bits of
+/// overhead per key.
+///
+/// The values live in one `Vec<V>` with a slot for every key, and whether each slot is absent,
+/// in progress or finished is kept in a separate bitset.  For small value types that is far
+/// more compact than a `Memoizer`, whose entries also carry bookkeeping for pinning,
+/// dependency tracking and eviction, so it suits tables of billions of tiny values.  Absent
+/// slots hold `V::default()`.  Lookups behave like `Memoizer::lookup()`, apart from the
+/// features this type leaves out.
+///
+/// ```
+/// use red_memo::PackedMemoizer;
+///
+/// // The length of the Collatz sequence from `n` down to 1, for `n` below the table size.
+/// fn steps(mem: &mut PackedMemoizer<u8>, n: &usize) -> u8 {
+///     let mut n = *n;
+///     let mut count = 0;
+///     while n != 1 {
+///         n = if n % 2 == 0 { n / 2 } else { 3 * n + 1 };
+///         count += 1;
+///         if n < 1000 {
+///             return count + mem.lookup(&n);
+///         }
+///     }
+///     count
+/// }
+///
+/// let mut mem = PackedMemoizer::new_dense_packed(1000, steps);
+/// assert_eq!(mem.lookup(&27), 111);
+/// assert_eq!(mem.lookup_immut(&1), Some(0));
+/// ```
+pub struct PackedMemoizer<'a, V: 'a> {
+    states: Vec<u64>,
+    values: Vec<V>,
+    bounds: DenseBounds,
+    sparse: HashMap<usize, MemoVal<V>>,
+    user_function: Rc<PackedFunction<'a, V>>,
+}
+
+impl<'a, V: 'a> Debug for PackedMemoizer<'a, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "PackedMemoizer {{ slots: {}, bounds: {:?}, sparse: {}, user_function: *unprintable* }}",
+            self.values.len(),
+            self.bounds,
+            self.sparse.len()
+        )
+    }
+}
+
+impl<'a, V: 'a + Clone + Default> PackedMemoizer<'a, V> {
+    /// Creates a PackedMemoizer for the keys `0..n`.
+    pub fn new_dense_packed<F>(n: usize, user: F) -> Self
+    where
+        F: 'a + Fn(&mut PackedMemoizer<V>, &usize) -> V,
+    {
+        PackedMemoizer {
+            states: vec![ABSENT; n.div_ceil(SLOTS_PER_WORD)],
+            values: vec![V::default(); n],
+            bounds: DenseBounds::Panic,
+            sparse: HashMap::new(),
+            user_function: Rc::new(user),
+        }
+    }
+    /// Sets what happens to keys outside the range of the table.
+    ///
+    /// With `DenseBounds::Grow`, a lookup of a key past the end extends the table to cover it,
+    /// which costs memory for every key in between.  With `DenseBounds::Fallback`, such keys
+    /// are cached in a `HashMap` instead, at a much higher cost per key than the table.
+    /// `lookup_immut()` never grows the table.
+    pub fn set_bounds(&mut self, bounds: DenseBounds) {
+        self.bounds = bounds;
+    }
+    /// Looks up a key in the cache, calculating a value if necessary.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if `k` is out of range and the bounds are `DenseBounds::Panic`,
+    /// or if a circular dependency is detected.
+    pub fn lookup(&mut self, k: &usize) -> V {
+        let k = *k;
+        if k >= self.values.len() {
+            match self.bounds {
+                DenseBounds::Panic => {}
+                DenseBounds::Grow => self.grow(k + 1),
+                DenseBounds::Fallback => return self.lookup_sparse(k),
+            }
+        }
+        match self.state(k) {
+            FINISHED => return self.values[k].clone(),
+            IN_PROGRESS => panic!("PackedMemoizer: circular dependency on key {:?}", k),
+            _ => {}
+        }
+        self.set_state(k, IN_PROGRESS);
+        let user = Rc::clone(&self.user_function);
+        let v = guarded(self, |mem| mem.set_state(k, ABSENT), |mem| (*user)(mem, &k));
+        self.values[k] = v.clone();
+        self.set_state(k, FINISHED);
+        v
+    }
+    /// Look up a key in the cache, but do not calculate it if it is not present.
+    ///
+    /// Returns `None` for keys out of range, unless they were cached with
+    /// `DenseBounds::Fallback`.
+    pub fn lookup_immut(&self, k: &usize) -> Option<V> {
+        if *k >= self.values.len() {
+            match self.sparse.get(k) {
+                Some(MemoVal::Finished(v)) => Some(v.clone()),
+                _ => None,
+            }
+        } else if self.state(*k) == FINISHED {
+            Some(self.values[*k].clone())
+        } else {
+            None
+        }
+    }
+    /// Changes the table to cover the keys `0..new_len`.
+    ///
+    /// Growing keeps every cached value, and the new keys start out absent.  Keys cached on
+    /// the side with `DenseBounds::Fallback` move into the table once it covers them.
+    /// Shrinking forgets the values of the keys cut off, unless the bounds are `Fallback`, in
+    /// which case they move to the side map.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it is called while a lookup is under way.
+    pub fn resize_dense(&mut self, new_len: usize) {
+        let busy = self.states.iter().any(|w| w & IN_PROGRESS_BITS != 0)
+            || self
+                .sparse
+                .values()
+                .any(|v| matches!(v, MemoVal::InProgress));
+        if busy {
+            panic!("PackedMemoizer: resize_dense() called while a lookup is under way");
+        }
+        let old_len = self.values.len();
+        if new_len < old_len {
+            if self.bounds == DenseBounds::Fallback {
+                for k in new_len..old_len {
+                    if self.state(k) == FINISHED {
+                        let v = std::mem::take(&mut self.values[k]);
+                        self.sparse.insert(k, MemoVal::Finished(v));
+                    }
+                }
+            }
+            self.values.truncate(new_len);
+            self.states.truncate(new_len.div_ceil(SLOTS_PER_WORD));
+            let used = new_len % SLOTS_PER_WORD;
+            if used != 0 {
+                if let Some(word) = self.states.last_mut() {
+                    *word &= (1 << (2 * used)) - 1;
+                }
+            }
+        } else {
+            self.grow(new_len);
+            let moved: Vec<usize> = self
+                .sparse
+                .keys()
+                .cloned()
+                .filter(|&k| k < new_len)
+                .collect();
+            for k in moved {
+                if let Some(MemoVal::Finished(v)) = self.sparse.remove(&k) {
+                    self.values[k] = v;
+                    self.set_state(k, FINISHED);
+                }
+            }
+        }
+    }
+    fn lookup_sparse(&mut self, k: usize) -> V {
+        match self.sparse.get(&k) {
+            Some(MemoVal::Finished(v)) => return v.clone(),
+            Some(MemoVal::InProgress) => {
+                panic!("PackedMemoizer: circular dependency on key {:?}", k)
+            }
+            Some(MemoVal::Known) | None => {}
+        }
+        self.sparse.insert(k, MemoVal::InProgress);
+        let user = Rc::clone(&self.user_function);
+        let v = guarded(
+            self,
+            |mem| {
+                mem.sparse.remove(&k);
+            },
+            |mem| (*user)(mem, &k),
+        );
+        self.sparse.insert(k, MemoVal::Finished(v.clone()));
+        v
+    }
+    fn grow(&mut self, n: usize) {
+        self.values.resize(n, V::default());
+        self.states.resize(n.div_ceil(SLOTS_PER_WORD), ABSENT);
+    }
+    fn state(&self, k: usize) -> u64 {
+        if k >= self.values.len() {
+            panic!(
+                "PackedMemoizer: key {} is out of range 0..{}",
+                k,
+                self.values.len()
+            );
+        }
+        (self.states[k / SLOTS_PER_WORD] >> (2 * (k % SLOTS_PER_WORD))) & 3
+    }
+    fn set_state(&mut self, k: usize, state: u64) {
+        let shift = 2 * (k % SLOTS_PER_WORD);
+        let word = &mut self.states[k / SLOTS_PER_WORD];
+        *word = (*word & !(3 << shift)) | (state << shift);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic_leaves_slot_absent() {
+        let mut mem = PackedMemoizer::new_dense_packed(100, |mem: &mut PackedMemoizer<u8>, k| {
+            if *k == 0 {
+                panic!("base case");
+            }
+            (mem.lookup(&(k - 1)) + 1) % 7
+        });
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mem.lookup(&40)));
+        assert!(result.is_err());
+        assert_eq!(mem.lookup_immut(&40), None);
+        assert_eq!(mem.lookup_immut(&1), None);
+        assert_eq!(mem.lookup_immut(&100), None);
+    }
+
+    #[test]
+    fn panic_leaves_sparse_key_absent() {
+        let mut mem = PackedMemoizer::new_dense_packed(10, |mem: &mut PackedMemoizer<u8>, k| {
+            if *k == 12 {
+                panic!("unavailable");
+            }
+            if *k == 0 {
+                0
+            } else {
+                mem.lookup(&(k - 1)) + 1
+            }
+        });
+        mem.set_bounds(DenseBounds::Fallback);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mem.lookup(&14)));
+        assert!(result.is_err());
+        assert!(mem.sparse.is_empty());
+        mem.resize_dense(20);
+        assert_eq!(mem.lookup(&11), 11);
+    }
+
+    #[test]
+    fn out_of_range_keys() {
+        let sum = |mem: &mut PackedMemoizer<u64>, k: &usize| {
+            if *k == 0 {
+                0
+            } else {
+                mem.lookup(&(k - 1)) + *k as u64
+            }
+        };
+        let mut mem = PackedMemoizer::new_dense_packed(10, sum);
+        mem.set_bounds(DenseBounds::Grow);
+        assert_eq!(mem.lookup(&100), 5050);
+        assert_eq!(mem.lookup_immut(&100), Some(5050));
+        assert_eq!(mem.lookup_immut(&101), None);
+
+        let mut mem = PackedMemoizer::new_dense_packed(10, sum);
+        mem.set_bounds(DenseBounds::Fallback);
+        assert_eq!(mem.lookup(&20), 210);
+        assert_eq!(mem.lookup_immut(&20), Some(210));
+        assert_eq!(mem.lookup_immut(&9), Some(45));
+        assert_eq!(mem.values.len(), 10);
+        assert_eq!(mem.sparse.len(), 11);
+    }
+
+    #[test]
+    fn resize_dense_keeps_values() {
+        let double = |_: &mut PackedMemoizer<u32>, k: &usize| 2 * *k as u32;
+        let mut mem = PackedMemoizer::new_dense_packed(40, double);
+        for k in 0..40 {
+            mem.lookup(&k);
+        }
+        mem.resize_dense(35);
+        assert_eq!(mem.lookup_immut(&34), Some(68));
+        mem.resize_dense(100);
+        assert_eq!(mem.lookup_immut(&34), Some(68));
+        assert_eq!(mem.lookup_immut(&35), None);
+        assert_eq!(mem.lookup(&99), 198);
+
+        let mut mem = PackedMemoizer::new_dense_packed(10, double);
+        mem.set_bounds(DenseBounds::Fallback);
+        mem.lookup(&5);
+        mem.lookup(&50);
+        mem.resize_dense(60);
+        assert!(mem.sparse.is_empty());
+        assert_eq!(mem.lookup_immut(&50), Some(100));
+        mem.resize_dense(3);
+        assert_eq!(mem.lookup_immut(&5), Some(10));
+        assert_eq!(mem.sparse.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "PackedMemoizer: circular dependency on key 3")]
+    fn cycle_panics() {
+        let mut mem =
+            PackedMemoizer::new_dense_packed(10, |mem: &mut PackedMemoizer<u8>, k| mem.lookup(k));
+        mem.lookup(&3);
+    }
+}
@@ -0,0 +1,308 @@
+//! A cache backend that keys on a hash of each key's serialization.
+
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hasher;
+use std::rc::Rc;
+
+use serde::Serialize;
+
+use crate::{MemoStruct, Memoizer};
+
+// A map from the hash of a key's serialized form to the keys with that hash and their values.
+// The keys themselves are kept so they can be handed back out by `iter()`, and so that with
+// `verify` set they can be told apart from the key being looked up, by serialization.  Without
+// `verify`, keys with the same hash are taken to be the same key, so each bucket holds at most
+// one entry; with it, colliding keys share a bucket.
+#[derive(Debug)]
+pub(crate) struct SerdeHashMap<K, V> {
+    map: HashMap<u64, Vec<(K, V)>>,
+    verify: bool,
+    // Probes whose hash was shared with another key, counted only with `verify`.
+    collisions: Cell<u64>,
+    hash: fn(&[u8]) -> u64,
+}
+
+impl<K, V> SerdeHashMap<K, V> {
+    pub(crate) fn new() -> Self {
+        SerdeHashMap::with_hash(default_hash)
+    }
+    fn with_hash(hash: fn(&[u8]) -> u64) -> Self {
+        SerdeHashMap {
+            map: HashMap::new(),
+            verify: false,
+            collisions: Cell::new(0),
+            hash,
+        }
+    }
+    // An empty map that hashes and verifies keys the same way as this one.
+    fn sibling<W>(&self) -> SerdeHashMap<K, W> {
+        let mut map = SerdeHashMap::with_hash(self.hash);
+        map.verify = self.verify;
+        map
+    }
+}
+
+fn default_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+fn serialized<K: Serialize + Debug>(k: &K) -> Vec<u8> {
+    bincode::serialize(k)
+        .unwrap_or_else(|e| panic!("Memoizer: key {:?} could not be serialized: {}", k, e))
+}
+
+impl<K: Serialize + Debug, V> SerdeHashMap<K, V> {
+    // Finds the hash of `k`, and the position of its entry in the bucket for that hash.
+    fn find(&self, k: &K) -> (u64, Option<usize>) {
+        let bytes = serialized(k);
+        let h = (self.hash)(&bytes);
+        let pos = self.map.get(&h).and_then(|bucket| {
+            if self.verify {
+                bucket
+                    .iter()
+                    .position(|(stored, _)| serialized(stored) == bytes)
+            } else {
+                (!bucket.is_empty()).then_some(0)
+            }
+        });
+        (h, pos)
+    }
+}
+
+impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> MemoStruct<'a, K, V> for SerdeHashMap<K, V>
+where
+    K: Serialize,
+{
+    fn insert(&mut self, k: K, v: V) -> Result<(), V> {
+        let (h, pos) = self.find(&k);
+        let bucket = self.map.entry(h).or_default();
+        match pos {
+            Some(i) => {
+                let (_, oldv) = std::mem::replace(&mut bucket[i], (k, v));
+                Err(oldv)
+            }
+            None => {
+                bucket.push((k, v));
+                Ok(())
+            }
+        }
+    }
+    fn get(&self, k: &K) -> Option<V> {
+        self.peek(k).cloned()
+    }
+    fn peek(&self, k: &K) -> Option<&V> {
+        match self.find(k) {
+            (h, Some(i)) => Some(&self.map[&h][i].1),
+            (_, None) => None,
+        }
+    }
+    fn probe(&self, k: &K) -> Option<&V> {
+        let (h, pos) = self.find(k);
+        let shared = self
+            .map
+            .get(&h)
+            .is_some_and(|bucket| bucket.len() > usize::from(pos.is_some()));
+        if self.verify && shared {
+            self.collisions.set(self.collisions.get() + 1);
+        }
+        pos.map(|i| &self.map[&h][i].1)
+    }
+    fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        match self.find(k) {
+            (h, Some(i)) => self.map.get_mut(&h).map(|bucket| &mut bucket[i].1),
+            (_, None) => None,
+        }
+    }
+    fn remove(&mut self, k: &K) -> Option<V> {
+        let (h, i) = match self.find(k) {
+            (h, Some(i)) => (h, i),
+            (_, None) => return None,
+        };
+        let bucket = self.map.get_mut(&h)?;
+        let (_, v) = bucket.swap_remove(i);
+        if bucket.is_empty() {
+            self.map.remove(&h);
+        }
+        Some(v)
+    }
+    fn iter<'s>(&'s self) -> Box<dyn 's + Iterator<Item = (&'s K, &'s V)>> {
+        Box::new(self.map.values().flatten().map(|(k, v)| (k, v)))
+    }
+    fn values_mut<'s>(&'s mut self) -> Box<dyn 's + Iterator<Item = &'s mut V>> {
+        Box::new(self.map.values_mut().flatten().map(|(_, v)| v))
+    }
+    fn new_index(&self) -> Box<dyn 'a + MemoStruct<'a, K, usize>> {
+        Box::new(self.sibling())
+    }
+    fn empty(&self) -> Box<dyn 'a + MemoStruct<'a, K, V>> {
+        Box::new(self.sibling())
+    }
+    fn into_entries(self: Box<Self>) -> Box<dyn 'a + Iterator<Item = (K, V)>> {
+        Box::new(self.map.into_values().flatten())
+    }
+    fn set_verify_keys(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+    fn collisions(&self) -> u64 {
+        self.collisions.get()
+    }
+}
+
+impl<'a, K: 'a + Clone + Debug + Serialize, V: 'a + Clone + Debug> Memoizer<'a, K, V> {
+    /// Creates a Memoizer that keys its cache on a hash of each key's serialization.
+    ///
+    /// The key type only needs `Serialize`, not `Hash + Eq` or `Ord`, so types that can't
+    /// implement those, like ones containing floats, can still be memoized.
+    ///
+    /// By default keys are never compared directly.  Two keys are treated as the same key
+    /// exactly when their serializations hash to the same 64-bit value, so two different keys
+    /// that happen to collide will share a cache entry and one of them will get the other's
+    /// value.  With a 64-bit hash that is extremely unlikely for any realistic number of keys,
+    /// but it can't be ruled out.  If a wrong answer is unacceptable, turn on `verify_keys()`,
+    /// or derive `Hash + Eq` or `Ord` for the key and use `new_hash()` or `new_ord()` instead.
+    ///
+    /// The converse problem is that keys which are equal but serialize differently, such as two
+    /// `HashMap`s holding the same entries in a different iteration order, get separate cache
+    /// entries.  That costs extra calculations, but never gives a wrong answer.
+    ///
+    /// This constructor is only available with the `serde` feature.
+    ///
+    /// # Panics
+    ///
+    /// Lookups panic if a key fails to serialize.
+    pub fn new_hash_serde<F>(user: F) -> Self
+    where
+        F: 'a + Fn(&mut Memoizer<K, V>, &K) -> V,
+    {
+        let cache = Box::new(SerdeHashMap::new());
+        let user_function = Rc::new(user);
+        Memoizer::with_cache(cache, user_function)
+    }
+
+    /// Turns on checking that a cache entry found for a key really belongs to it.
+    ///
+    /// With a Memoizer created by `new_hash_serde()`, each lookup that finds entries under its
+    /// key's hash also serializes the keys stored with them and compares the serializations,
+    /// so keys whose hashes collide get separate entries and never each other's values.  A
+    /// lookup whose hash is shared with another key is counted in `key_collisions()`.  The
+    /// check costs a second serialization per entry compared.  Turning it off again makes keys
+    /// with the same hash share an entry from then on.
+    ///
+    /// The other backends always compare keys with `Eq` or `Ord`, so for them this does
+    /// nothing.  This method is only available with the `serde` feature.
+    pub fn verify_keys(&mut self, verify: bool) {
+        self.cache.set_verify_keys(verify);
+        self.in_flight.set_verify_keys(verify);
+    }
+
+    /// Returns the number of lookups whose key's hash was shared by another key's entry.
+    ///
+    /// Only lookups made with `lookup()` and its variants are counted, not `lookup_immut()` or
+    /// the Memoizer's own bookkeeping.  Collisions are only detected while `verify_keys()` is
+    /// on, and this is always zero for backends other than `new_hash_serde()`.  Anything but
+    /// zero means the hash is too weak for the keys in use.
+    pub fn key_collisions(&self) -> u64 {
+        self.cache.collisions()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Number of lattice paths from (0, 0) to a point, moving in unit steps up or right.
+    fn paths(mem: &mut Memoizer<(f64, f64), u64>, k: &(f64, f64)) -> u64 {
+        let (x, y) = *k;
+        if x == 0.0 || y == 0.0 {
+            1
+        } else {
+            mem.lookup(&(x - 1.0, y)) + mem.lookup(&(x, y - 1.0))
+        }
+    }
+
+    #[test]
+    fn float_keys() {
+        let mut mem = Memoizer::new_hash_serde(paths);
+        assert_eq!(mem.lookup(&(16.0, 16.0)), 601080390);
+        assert_eq!(mem.lookup_immut(&(3.0, 2.0)), Some(10));
+        assert_eq!(mem.lookup_immut(&(3.5, 2.0)), None);
+    }
+
+    #[test]
+    fn verified_keys_survive_collisions() {
+        // Every key of the same length collides.
+        let cache = Box::new(SerdeHashMap::with_hash(|bytes| bytes.len() as u64));
+        let mut mem = Memoizer::with_cache(cache, Rc::new(paths));
+        mem.verify_keys(true);
+        assert_eq!(mem.lookup(&(2.0, 2.0)), 6);
+        assert_eq!(mem.lookup(&(3.0, 3.0)), 20);
+        assert!(mem.key_collisions() > 0);
+        assert_eq!(mem.len_finished(), 15);
+        assert_eq!(mem.lookup_immut(&(1.0, 2.0)), Some(3));
+    }
+
+    #[test]
+    fn collisions_counted_once_per_lookup() {
+        let cache = Box::new(SerdeHashMap::with_hash(|_| 0));
+        let mut mem = Memoizer::with_cache(
+            cache,
+            Rc::new(|_: &mut Memoizer<(f64, f64), f64>, k: &(f64, f64)| k.0 + k.1),
+        );
+        mem.verify_keys(true);
+        assert_eq!(mem.lookup(&(1.0, 2.0)), 3.0);
+        assert_eq!(mem.key_collisions(), 0);
+        assert_eq!(mem.lookup(&(2.0, 2.0)), 4.0);
+        assert_eq!(mem.key_collisions(), 1);
+        assert_eq!(mem.lookup(&(1.0, 2.0)), 3.0);
+        assert_eq!(mem.lookup_immut(&(2.0, 2.0)), Some(4.0));
+        assert!(mem.is_finished(&(2.0, 2.0)));
+        assert_eq!(mem.key_collisions(), 2);
+    }
+
+    #[test]
+    fn colliding_mutual_dependencies_are_cycles() {
+        let cache = Box::new(SerdeHashMap::with_hash(|_| 0));
+        let mut mem = Memoizer::with_cache(cache, Rc::new(paths));
+        mem.verify_keys(true);
+        let deps = |k: &(f64, f64)| vec![(k.1, k.0)];
+        let err = mem.warm(&[(1.0, 2.0)], deps).unwrap_err();
+        assert!(err.key == (1.0, 2.0) || err.key == (2.0, 1.0));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            mem.lookup_iterative(&(1.0, 2.0), deps, |_, vs: &[u64]| vs[0])
+        }));
+        assert!(result.is_err());
+        assert_eq!(mem.len_finished(), 0);
+    }
+
+    #[test]
+    fn collisions_spare_in_progress_entries() {
+        // Every key collides, so the outer key's marker is in the way of the inner one.
+        let cache = Box::new(SerdeHashMap::with_hash(|_| 0));
+        let mut mem = Memoizer::with_cache(
+            cache,
+            Rc::new(|mem: &mut Memoizer<(f64, f64), u64>, k: &(f64, f64)| {
+                mem.lookup(&(1.0 - k.0, 0.0))
+            }),
+        );
+        mem.verify_keys(true);
+        let err = mem.try_lookup(&(0.0, 0.0)).unwrap_err();
+        assert_eq!(err, crate::LookupError::Cycle(crate::CycleError { key: (0.0, 0.0) }));
+        assert_eq!(mem.len_finished(), 0);
+    }
+
+    #[test]
+    fn collisions_keep_recency_in_step() {
+        let cache = Box::new(SerdeHashMap::with_hash(|_| 0));
+        let mut mem = Memoizer::with_cache(cache, Rc::new(paths));
+        mem.verify_keys(true);
+        mem.set_capacity(3);
+        assert_eq!(mem.lookup(&(3.0, 3.0)), 20);
+        assert_eq!(mem.len_finished(), 3);
+        assert_eq!(mem.next_victim(), Some(&(3.0, 1.0)));
+    }
+}
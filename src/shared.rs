@@ -0,0 +1,125 @@
+//! A single-threaded handle for sharing one Memoizer between several owners.
+
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use crate::Memoizer;
+
+/// A cloneable handle to a shared `Memoizer`.
+///
+/// This packages the usual `Rc<RefCell<Memoizer>>` pattern for code where several closures,
+/// such as GUI callbacks, need the same cache.  Cloning the handle is cheap and all clones see
+/// the same cache.  `SharedMemoizer` is neither `Send` nor `Sync`; use `SyncMemoizer` to share
+/// a cache between threads.
+///
+/// Every method borrows the Memoizer for the duration of the call, so the one thing that can
+/// go wrong is re-entering the handle while a lookup is running.  A user function must make its
+/// nested lookups through the `&mut Memoizer` it is given, never through a `SharedMemoizer`
+/// that it captured, and a `with()` closure must use its own argument.  Re-entering the handle
+/// panics with a message saying so, rather than with `RefCell`'s bare "already borrowed".
+///
+/// ```
+/// use red_memo::{Memoizer, SharedMemoizer};
+///
+/// fn fibonacci(mem: &mut Memoizer<u64, u64>, k: &u64) -> u64 {
+///     let k = *k;
+///     if k < 2 {
+///         k
+///     } else {
+///         mem.lookup(&(k - 1)) + mem.lookup(&(k - 2))
+///     }
+/// }
+///
+/// let shared = SharedMemoizer::new(Memoizer::new_hash(fibonacci));
+/// let on_click = {
+///     let shared = shared.clone();
+///     move |k: u64| shared.lookup(&k)
+/// };
+/// assert_eq!(on_click(30), 832040);
+/// assert_eq!(shared.lookup_immut(&29), Some(514229));
+/// ```
+pub struct SharedMemoizer<'a, K: 'a, V: 'a + Clone + Debug> {
+    memo: Rc<RefCell<Memoizer<'a, K, V>>>,
+}
+
+impl<'a, K: 'a, V: 'a + Clone + Debug> Clone for SharedMemoizer<'a, K, V> {
+    fn clone(&self) -> Self {
+        SharedMemoizer {
+            memo: Rc::clone(&self.memo),
+        }
+    }
+}
+
+impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> Debug for SharedMemoizer<'a, K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.memo.try_borrow() {
+            Ok(memo) => write!(f, "SharedMemoizer {{ memo: {:?} }}", memo),
+            Err(_) => write!(f, "SharedMemoizer {{ memo: *in use* }}"),
+        }
+    }
+}
+
+impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> SharedMemoizer<'a, K, V> {
+    /// Wraps a Memoizer in a new shared handle.
+    pub fn new(memo: Memoizer<'a, K, V>) -> Self {
+        SharedMemoizer {
+            memo: Rc::new(RefCell::new(memo)),
+        }
+    }
+    /// Looks up a key in the shared cache, calculating a value if necessary.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `Memoizer::lookup()`, and if the handle is re-entered while
+    /// another call through it is running.
+    pub fn lookup(&self, k: &K) -> V {
+        self.with(|memo| memo.lookup(k))
+    }
+    /// Look up a key in the shared cache, but do not calculate it if it is not present.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the handle is re-entered while another call through it is running.
+    pub fn lookup_immut(&self, k: &K) -> Option<V> {
+        self.with(|memo| memo.lookup_immut(k))
+    }
+    /// Runs `f` with exclusive access to the shared Memoizer, for everything `lookup()` doesn't
+    /// cover.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the handle is re-entered while another call through it is running.
+    pub fn with<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Memoizer<'a, K, V>) -> R,
+    {
+        let mut memo = self.memo.try_borrow_mut().unwrap_or_else(|_| {
+            panic!(
+                "SharedMemoizer: re-entered while in use; nested lookups must go through the \
+                 &mut Memoizer passed to the user function"
+            )
+        });
+        f(&mut memo)
+    }
+}
+
+impl<'a, K: 'a + Clone + Debug, V: 'a + Clone + Debug> From<Memoizer<'a, K, V>>
+    for SharedMemoizer<'a, K, V>
+{
+    fn from(memo: Memoizer<'a, K, V>) -> Self {
+        SharedMemoizer::new(memo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "SharedMemoizer: re-entered while in use")]
+    fn reentry_panics() {
+        let shared = SharedMemoizer::new(Memoizer::new_hash(|_: &mut Memoizer<u64, u64>, k| *k));
+        shared.with(|_| shared.lookup(&1));
+    }
+}
@@ -0,0 +1,724 @@
+//! A thread-safe memoizer that can be shared between threads or stored in a `static`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::MemoStruct;
+
+type SyncUserFunction<K, V> = dyn Send + Sync + Fn(&SyncMemoizer<K, V>, &K) -> V;
+
+// The threads calculating a key, each with the number of its calculation, which orders the
+// calculations by when they started.
+type Calculating = Vec<(ThreadId, u64)>;
+
+// The keys being calculated, on every thread.
+struct InProgress<K: 'static + Clone + Debug> {
+    keys: Box<dyn Send + MemoStruct<'static, K, Calculating>>,
+    // The number of calculations started so far, used to number them.
+    started: u64,
+}
+
+impl<K: 'static + Clone + Debug> InProgress<K> {
+    fn new<M: 'static + Send + MemoStruct<'static, K, Calculating>>(keys: M) -> Self {
+        InProgress {
+            keys: Box::new(keys),
+            started: 0,
+        }
+    }
+    // Whether `thread` is calculating `k`.
+    fn is_calculating(&self, thread: ThreadId, k: &K) -> bool {
+        self.keys
+            .peek(k)
+            .is_some_and(|threads| threads.iter().any(|&(t, _)| t == thread))
+    }
+    fn start(&mut self, thread: ThreadId, k: &K) {
+        self.started += 1;
+        let calculation = (thread, self.started);
+        match self.keys.get_mut(k) {
+            Some(threads) => threads.push(calculation),
+            None => {
+                let _ = self.keys.insert(k.clone(), vec![calculation]);
+            }
+        }
+    }
+    fn finish(&mut self, thread: ThreadId, k: &K) {
+        let threads = match self.keys.get_mut(k) {
+            Some(threads) => threads,
+            None => return,
+        };
+        if let Some(pos) = threads.iter().rposition(|&(t, _)| t == thread) {
+            threads.remove(pos);
+        }
+        if threads.is_empty() {
+            self.keys.remove(k);
+        }
+    }
+}
+
+// Where a SyncMemoizer keeps its finished values.
+trait SyncStore<K, V>: Send + Sync + Debug {
+    fn get(&self, k: &K) -> Option<V>;
+    // Stores `v` unless `k` already has a value.  Returns `v` if it was stored, and the value
+    // already there as an error otherwise.
+    fn insert_first(&self, k: K, v: V) -> Result<V, V>;
+    // Stores `v`, returning the value it replaced.
+    fn replace(&self, k: K, v: V) -> Option<V>;
+}
+
+// One of the single-threaded backends behind a mutex.
+struct Locked<K: 'static + Clone + Debug, V: 'static + Clone + Debug> {
+    map: Mutex<Box<dyn Send + MemoStruct<'static, K, V>>>,
+}
+
+impl<K: 'static + Clone + Debug, V: 'static + Clone + Debug> Locked<K, V> {
+    fn new<M: 'static + Send + MemoStruct<'static, K, V>>(map: M) -> Self {
+        Locked {
+            map: Mutex::new(Box::new(map)),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, Box<dyn Send + MemoStruct<'static, K, V>>> {
+        // Every operation on the map is a single call, so a poisoned lock is still usable.
+        self.map.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<K: 'static + Clone + Debug + Send, V: 'static + Clone + Debug + Send> Debug for Locked<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self.lock())
+    }
+}
+
+impl<K: 'static + Clone + Debug + Send, V: 'static + Clone + Debug + Send> SyncStore<K, V>
+    for Locked<K, V>
+{
+    fn get(&self, k: &K) -> Option<V> {
+        self.lock().get(k)
+    }
+    fn insert_first(&self, k: K, v: V) -> Result<V, V> {
+        let mut map = self.lock();
+        match map.get(&k) {
+            Some(existing) => Err(existing),
+            None => {
+                let _ = map.insert(k, v.clone());
+                Ok(v)
+            }
+        }
+    }
+    fn replace(&self, k: K, v: V) -> Option<V> {
+        self.lock().insert(k, v).err()
+    }
+}
+
+// A concurrent skip list whose values are stamped with the number of the insertion that put
+// them there, so an insertion can tell whether the value kept for a key is its own.
+#[cfg(feature = "concurrent")]
+struct SkipList<K, V> {
+    map: crossbeam_skiplist::SkipMap<K, (u64, V)>,
+    stamps: AtomicU64,
+}
+
+#[cfg(feature = "concurrent")]
+impl<K: 'static + Ord + Send, V: 'static + Send> SkipList<K, V> {
+    fn new() -> Self {
+        SkipList {
+            map: crossbeam_skiplist::SkipMap::new(),
+            stamps: AtomicU64::new(0),
+        }
+    }
+    fn stamp(&self, v: V) -> (u64, V) {
+        (self.stamps.fetch_add(1, Ordering::Relaxed), v)
+    }
+}
+
+#[cfg(feature = "concurrent")]
+impl<K: Debug + Ord, V: Debug> Debug for SkipList<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut map = f.debug_map();
+        for e in self.map.iter() {
+            map.entry(e.key(), &e.value().1);
+        }
+        map.finish()
+    }
+}
+
+#[cfg(feature = "concurrent")]
+impl<K, V> SyncStore<K, V> for SkipList<K, V>
+where
+    K: 'static + Clone + Debug + Ord + Send + Sync,
+    V: 'static + Clone + Debug + Send + Sync,
+{
+    fn get(&self, k: &K) -> Option<V> {
+        self.map.get(k).map(|e| e.value().1.clone())
+    }
+    fn insert_first(&self, k: K, v: V) -> Result<V, V> {
+        let (stamp, v) = self.stamp(v);
+        // The lookup and the insertion are one atomic step, and the stamp shows which value won.
+        let e = self.map.get_or_insert(k, (stamp, v));
+        let (kept, v) = e.value();
+        if *kept == stamp {
+            Ok(v.clone())
+        } else {
+            Err(v.clone())
+        }
+    }
+    fn replace(&self, k: K, v: V) -> Option<V> {
+        // The comparison is called with each value found in the way, and the last of them is the
+        // one the new value replaced.
+        let old = std::cell::RefCell::new(None);
+        self.map.compare_insert(k, self.stamp(v), |(_, found)| {
+            *old.borrow_mut() = Some(found.clone());
+            true
+        });
+        old.into_inner()
+    }
+}
+
+/// Thread-safe memoization cache for a recursive user function.
+///
+/// `SyncMemoizer` is `Send + Sync` whenever `K` and `V` are `Send`, so it can be shared through
+/// an `Arc` or stored in a `static` with `std::sync::OnceLock`.  The user function receives a
+/// shared reference to the `SyncMemoizer`, and `lookup()` only needs `&self`.
+///
+/// The cache is protected by a mutex which is never held while the user function runs.  Cycle
+/// detection is done per thread, so a key being computed on one thread does not look like a
+/// circular dependency to another.  If two threads miss on the same key at the same time, both
+/// compute it and the first value stored is kept, unless `set_single_flight(true)` makes the
+/// second one wait for the first.
+///
+/// ```
+/// use std::sync::OnceLock;
+/// use red_memo::SyncMemoizer;
+///
+/// fn fibonacci(mem: &SyncMemoizer<u64, u64>, k: &u64) -> u64 {
+///     let k = *k;
+///     if k < 2 {
+///         k
+///     } else {
+///         mem.lookup(&(k - 1)) + mem.lookup(&(k - 2))
+///     }
+/// }
+///
+/// static FIB: OnceLock<SyncMemoizer<u64, u64>> = OnceLock::new();
+///
+/// fn fib(k: u64) -> u64 {
+///     FIB.get_or_init(|| SyncMemoizer::new_hash(fibonacci)).lookup(&k)
+/// }
+///
+/// assert_eq!(fib(40), 102334155);
+/// ```
+pub struct SyncMemoizer<K: 'static + Clone + Debug, V: 'static + Clone + Debug> {
+    values: Box<dyn SyncStore<K, V>>,
+    in_progress: Mutex<InProgress<K>>,
+    // Notified, with `in_progress` locked, whenever a value is added to the cache.
+    filled: Condvar,
+    // Calculated values discarded because another thread had stored the key first.
+    duplicates: AtomicU64,
+    // Set through a shared reference, so it can be turned on for a memoizer in a `static`.
+    single_flight: AtomicBool,
+    user_function: Box<SyncUserFunction<K, V>>,
+}
+
+impl<K: 'static + Clone + Debug + Eq + Send, V: 'static + Clone + Debug + Send> Debug
+    for SyncMemoizer<K, V>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "SyncMemoizer {{ cache: {:?}, user_function: *unprintable* }}",
+            self.values
+        )
+    }
+}
+
+impl<K: 'static + Clone + Debug + Eq + Send, V: 'static + Clone + Debug + Send> SyncMemoizer<K, V> {
+    /// Creates a SyncMemoizer based on HashMap.
+    pub fn new_hash<F>(user: F) -> Self
+    where
+        K: Hash,
+        F: 'static + Send + Sync + Fn(&SyncMemoizer<K, V>, &K) -> V,
+    {
+        SyncMemoizer::with_values(
+            Box::new(Locked::new(HashMap::new())),
+            InProgress::new(HashMap::new()),
+            Box::new(user),
+        )
+    }
+    /// Creates a SyncMemoizer based on a BTreeMap.
+    pub fn new_ord<F>(user: F) -> Self
+    where
+        K: Ord,
+        F: 'static + Send + Sync + Fn(&SyncMemoizer<K, V>, &K) -> V,
+    {
+        SyncMemoizer::with_values(
+            Box::new(Locked::new(BTreeMap::new())),
+            InProgress::new(BTreeMap::new()),
+            Box::new(user),
+        )
+    }
+    /// Creates a SyncMemoizer whose finished values are kept in a concurrent skip list.
+    ///
+    /// With the other backends every lookup, hit or miss, takes a lock on the whole cache.
+    /// Here cache hits are lock-free, so read-heavy workloads scale across threads.  Misses
+    /// still take a lock to record the in-progress key for cycle detection, and again to wake
+    /// threads waiting in `lookup_or_wait()`, but never while the user function runs.
+    ///
+    /// In-progress markers are not visible to other threads: a thread that misses on a key
+    /// calculates it even if another thread is already calculating it, and whichever value is
+    /// inserted first is kept and returned to both.  A finished value becomes visible to every
+    /// thread as soon as it is inserted.
+    ///
+    /// This constructor is only available with the `concurrent` feature.
+    #[cfg(feature = "concurrent")]
+    pub fn new_ord_concurrent<F>(user: F) -> Self
+    where
+        K: Ord + Sync,
+        V: Sync,
+        F: 'static + Send + Sync + Fn(&SyncMemoizer<K, V>, &K) -> V,
+    {
+        SyncMemoizer::with_values(
+            Box::new(SkipList::new()),
+            InProgress::new(BTreeMap::new()),
+            Box::new(user),
+        )
+    }
+    fn with_values(
+        values: Box<dyn SyncStore<K, V>>,
+        in_progress: InProgress<K>,
+        user_function: Box<SyncUserFunction<K, V>>,
+    ) -> Self {
+        SyncMemoizer {
+            values,
+            in_progress: Mutex::new(in_progress),
+            filled: Condvar::new(),
+            duplicates: AtomicU64::new(0),
+            single_flight: AtomicBool::new(false),
+            user_function,
+        }
+    }
+    /// Looks up a key in the cache, calculating a value if necessary.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if a circular dependency is detected on the calling thread.
+    pub fn lookup(&self, k: &K) -> V {
+        if let Some(v) = self.values.get(k) {
+            return v;
+        }
+        let me = thread::current().id();
+        {
+            let mut in_progress = self.lock();
+            loop {
+                if in_progress.is_calculating(me, k) {
+                    panic!("SyncMemoizer: circular dependency on key {:?}", k);
+                }
+                if !self.single_flight.load(Ordering::Relaxed) {
+                    break;
+                }
+                // Another thread may have stored the key since the first check.
+                if let Some(v) = self.values.get(k) {
+                    return v;
+                }
+                if in_progress.keys.peek(k).is_none() {
+                    break;
+                }
+                in_progress = self
+                    .filled
+                    .wait(in_progress)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            in_progress.start(me, k);
+        }
+        let guard = InProgressGuard {
+            memo: self,
+            thread: me,
+            key: k,
+        };
+        let v = (self.user_function)(self, k);
+        let v = match self.values.insert_first(k.clone(), v) {
+            Ok(v) => v,
+            Err(existing) => {
+                self.duplicates.fetch_add(1, Ordering::Relaxed);
+                existing
+            }
+        };
+        // The value is stored before the marker is removed, so a thread waiting for this key
+        // always finds it.  Dropping the guard wakes the waiting threads.
+        drop(guard);
+        v
+    }
+    /// Makes a thread that misses on a key another thread is calculating wait for that value,
+    /// instead of calculating it as well.  This is off by default.
+    ///
+    /// With single-flight on, every key is calculated at most once however many threads want
+    /// it at the same time, which saves the duplicated work counted by
+    /// `duplicate_computations()` at the cost of some waiting.  A circular dependency whose
+    /// keys are split between threads can't be detected, so with a cyclic recurrence the threads
+    /// involved wait for each other forever instead of panicking.
+    ///
+    /// With `new_ord_concurrent()`, cache hits don't look at the in-progress keys, so they stay
+    /// lock-free; only misses wait.
+    ///
+    /// This only takes `&self`, so it can be changed on a SyncMemoizer that is already shared
+    /// through an `Arc` or a `static`.  Lookups that have already started waiting, or decided
+    /// not to, are not affected.
+    pub fn set_single_flight(&self, enabled: bool) {
+        self.single_flight.store(enabled, Ordering::Relaxed);
+    }
+    /// Looks up every key in `keys` on several threads, returning the values in the same order.
+    ///
+    /// The keys are split into one chunk per available CPU and each chunk is looked up on its
+    /// own scoped thread, so the queries should be independent top-level lookups.  They share
+    /// the cache as they go, and with `set_single_flight(true)` a dependency that several
+    /// queries need at once is calculated only once.
+    ///
+    /// # Panics
+    ///
+    /// If a lookup panics on one of the threads, the panic is propagated once the other threads
+    /// have finished.
+    pub fn par_lookup_all(&self, keys: &[K]) -> Vec<V>
+    where
+        K: Sync,
+    {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk = keys.len().div_ceil(threads).max(1);
+        thread::scope(|s| {
+            let handles: Vec<_> = keys
+                .chunks(chunk)
+                .map(|keys| {
+                    s.spawn(move || keys.iter().map(|k| self.lookup(k)).collect::<Vec<V>>())
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                .collect()
+        })
+    }
+    /// Looks up a key, waiting up to `timeout` for another thread to fill it before
+    /// calculating it locally.
+    ///
+    /// This is for caches that are also filled from outside, for example by a thread that
+    /// receives values computed on other nodes and passes them to `store()`.  A miss waits for
+    /// the key to appear, waking whenever any value is added, and falls back to `lookup()` if
+    /// it is still missing once the timeout has passed.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `lookup()`.
+    pub fn lookup_or_wait(&self, k: &K, timeout: Duration) -> V {
+        let deadline = Instant::now() + timeout;
+        let mut in_progress = self.lock();
+        loop {
+            if let Some(v) = self.values.get(k) {
+                return v;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            in_progress = self
+                .filled
+                .wait_timeout(in_progress, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        drop(in_progress);
+        self.lookup(k)
+    }
+    /// Stores a value for a key directly, without calling the user function.
+    ///
+    /// Returns the value previously stored for `k`, if there was one.  Threads waiting in
+    /// `lookup_or_wait()` are woken.
+    pub fn store(&self, k: K, v: V) -> Option<V> {
+        let old = self.values.replace(k, v);
+        self.notify_filled();
+        old
+    }
+    /// Calculates a layered computation one layer at a time, spreading each layer's keys over
+    /// several threads.
+    ///
+    /// The keys within a layer must not depend on each other, only on keys in earlier layers.
+    /// Each layer is split into one chunk per available CPU, the chunks are looked up on scoped
+    /// threads, and the next layer starts once they have all finished, so every lookup of an
+    /// earlier layer's key is a cache hit.  Lookups of keys that were not in an earlier layer
+    /// still work, but may be calculated on more than one thread.
+    ///
+    /// # Panics
+    ///
+    /// If a lookup panics on one of the threads, the panic is propagated once the rest of the
+    /// layer has finished, and later layers are not calculated.
+    pub fn compute_layers(&self, layers: Vec<Vec<K>>)
+    where
+        K: Sync,
+    {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        for layer in layers {
+            let chunk = layer.len().div_ceil(threads).max(1);
+            thread::scope(|s| {
+                for keys in layer.chunks(chunk) {
+                    s.spawn(move || {
+                        for k in keys {
+                            self.lookup(k);
+                        }
+                    });
+                }
+            });
+        }
+    }
+    /// Returns the number of calculations whose value was discarded because another thread
+    /// stored the same key while they ran.
+    ///
+    /// Each one is a calculation that single-flight deduplication would have saved, so this
+    /// measures how much work racing threads duplicate.
+    pub fn duplicate_computations(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+    /// Returns the keys being calculated right now, on every thread.
+    ///
+    /// The keys are in the order their calculations started, so the keys of any one thread
+    /// appear in call-stack order, outermost first.  This only takes the in-progress lock
+    /// briefly, so it can be called from a monitoring thread to log what a long or stuck
+    /// computation is doing, without disturbing it.
+    pub fn in_progress_keys_snapshot(&self) -> Vec<K> {
+        let in_progress = self.lock();
+        let mut keys: Vec<(u64, &K)> = in_progress
+            .keys
+            .iter()
+            .flat_map(|(k, threads)| threads.iter().map(move |&(_, started)| (started, k)))
+            .collect();
+        keys.sort_unstable_by_key(|&(started, _)| started);
+        keys.into_iter().map(|(_, k)| k.clone()).collect()
+    }
+    /// Look up a key in the cache, but do not calculate it if it is not present.
+    pub fn lookup_immut(&self, k: &K) -> Option<V> {
+        self.values.get(k)
+    }
+    fn lock(&self) -> MutexGuard<'_, InProgress<K>> {
+        // The keys are never left inconsistent by a panic, so a poisoned lock is still usable.
+        self.in_progress
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    // Wakes the threads in `lookup_or_wait()`.  Taking the lock first means a waiter can't
+    // miss a value inserted between its last check and its wait.
+    fn notify_filled(&self) {
+        let _in_progress = self.lock();
+        self.filled.notify_all();
+    }
+}
+
+// Removes a thread's in-progress marker even if the user function panics.
+struct InProgressGuard<
+    'm,
+    K: 'static + Clone + Debug + Eq + Send,
+    V: 'static + Clone + Debug + Send,
+> {
+    memo: &'m SyncMemoizer<K, V>,
+    thread: ThreadId,
+    key: &'m K,
+}
+
+impl<'m, K: 'static + Clone + Debug + Eq + Send, V: 'static + Clone + Debug + Send> Drop
+    for InProgressGuard<'m, K, V>
+{
+    fn drop(&mut self) {
+        let mut in_progress = self.memo.lock();
+        in_progress.finish(self.thread, self.key);
+        // Whether the value was stored or the user function panicked, threads waiting for the
+        // key can stop waiting.
+        self.memo.filled.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, OnceLock};
+
+    fn fibonacci(mem: &SyncMemoizer<u64, u64>, k: &u64) -> u64 {
+        let k = *k;
+        if k < 2 {
+            k
+        } else {
+            mem.lookup(&(k - 1)) + mem.lookup(&(k - 2))
+        }
+    }
+
+    #[test]
+    fn fibs_across_threads() {
+        let fib_cache = Arc::new(SyncMemoizer::new_ord(fibonacci));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let fib_cache = Arc::clone(&fib_cache);
+                thread::spawn(move || fib_cache.lookup(&(30 + i)))
+            })
+            .collect();
+        let results: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, vec![832040, 1346269, 2178309, 3524578]);
+        assert_eq!(fib_cache.lookup_immut(&20), Some(6765));
+    }
+
+    #[test]
+    fn in_progress_keys_seen_from_another_thread() {
+        use std::sync::Barrier;
+        let (reached, release) = (Arc::new(Barrier::new(2)), Arc::new(Barrier::new(2)));
+        let (at_base, held) = (Arc::clone(&reached), Arc::clone(&release));
+        let mem = SyncMemoizer::new_hash(move |mem: &SyncMemoizer<u64, u64>, k: &u64| {
+            if *k == 0 {
+                at_base.wait();
+                held.wait();
+                0
+            } else {
+                mem.lookup(&(k - 1)) + 1
+            }
+        });
+        thread::scope(|s| {
+            let worker = s.spawn(|| mem.lookup(&3));
+            reached.wait();
+            assert_eq!(mem.in_progress_keys_snapshot(), vec![3, 2, 1, 0]);
+            release.wait();
+            assert_eq!(worker.join().unwrap(), 3);
+        });
+        assert!(mem.in_progress_keys_snapshot().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "SyncMemoizer: circular dependency on key 3")]
+    fn self_cycle_panics() {
+        let mem = SyncMemoizer::new_hash(|mem: &SyncMemoizer<u64, u64>, k: &u64| mem.lookup(k));
+        mem.lookup(&3);
+    }
+
+    #[test]
+    fn layered_binomials() {
+        let mem = SyncMemoizer::new_hash(|mem: &SyncMemoizer<(u64, u64), u64>, &(n, k)| {
+            if k == 0 || k == n {
+                1
+            } else {
+                let above = mem
+                    .lookup_immut(&(n - 1, k - 1))
+                    .zip(mem.lookup_immut(&(n - 1, k)));
+                let (a, b) = above.expect("previous layer was not finished");
+                a + b
+            }
+        });
+        let layers = (0..40).map(|n| (0..=n).map(|k| (n, k)).collect()).collect();
+        mem.compute_layers(layers);
+        assert_eq!(mem.lookup_immut(&(39, 19)), Some(68923264410));
+    }
+
+    #[test]
+    fn wait_for_external_fill() {
+        let mem = Arc::new(SyncMemoizer::new_hash(
+            |_: &SyncMemoizer<u64, u64>, _: &u64| 0,
+        ));
+        let filler = {
+            let mem = Arc::clone(&mem);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                mem.store(7, 49);
+            })
+        };
+        assert_eq!(mem.lookup_or_wait(&7, Duration::from_secs(60)), 49);
+        filler.join().unwrap();
+        assert_eq!(mem.lookup_or_wait(&8, Duration::from_millis(1)), 0);
+    }
+
+    #[test]
+    fn racing_threads_count_duplicates() {
+        use std::sync::Barrier;
+        let barrier = Arc::new(Barrier::new(2));
+        let mem = {
+            let barrier = Arc::clone(&barrier);
+            Arc::new(SyncMemoizer::new_hash(
+                move |_: &SyncMemoizer<u64, u64>, k: &u64| {
+                    barrier.wait();
+                    k * 2
+                },
+            ))
+        };
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let mem = Arc::clone(&mem);
+                thread::spawn(move || mem.lookup(&21))
+            })
+            .collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), 42);
+        }
+        assert_eq!(mem.duplicate_computations(), 1);
+    }
+
+    #[test]
+    fn single_flight_calculates_each_key_once() {
+        use std::sync::atomic::AtomicUsize;
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static MEM: OnceLock<SyncMemoizer<u64, u64>> = OnceLock::new();
+        let mem = MEM.get_or_init(|| {
+            SyncMemoizer::new_hash(|mem: &SyncMemoizer<u64, u64>, k: &u64| {
+                CALLS.fetch_add(1, Ordering::Relaxed);
+                if *k < 2 {
+                    *k
+                } else {
+                    thread::sleep(Duration::from_millis(1));
+                    mem.lookup(&(k - 1)) + mem.lookup(&(k - 2))
+                }
+            })
+        });
+        // A memoizer in a `static` can only be reached through a shared reference.
+        mem.set_single_flight(true);
+        let keys: Vec<u64> = (0..16).rev().chain(0..16).collect();
+        let fibs = mem.par_lookup_all(&keys);
+        assert_eq!(fibs[0], 610);
+        assert_eq!(fibs[16..20], [0, 1, 1, 2]);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 16);
+        assert_eq!(mem.duplicate_computations(), 0);
+    }
+
+    #[cfg(feature = "concurrent")]
+    #[test]
+    fn concurrent_fibs_across_threads() {
+        let fib_cache = Arc::new(SyncMemoizer::new_ord_concurrent(fibonacci));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let fib_cache = Arc::clone(&fib_cache);
+                thread::spawn(move || fib_cache.lookup(&(30 + i)))
+            })
+            .collect();
+        let results: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, vec![832040, 1346269, 2178309, 3524578]);
+        assert_eq!(fib_cache.store(20, 0), Some(6765));
+        assert_eq!(fib_cache.lookup_immut(&20), Some(0));
+    }
+
+    #[cfg(feature = "concurrent")]
+    #[test]
+    fn concurrent_racing_threads_count_duplicates() {
+        use std::sync::Barrier;
+        let barrier = Arc::new(Barrier::new(2));
+        let mem = {
+            let barrier = Arc::clone(&barrier);
+            Arc::new(SyncMemoizer::new_ord_concurrent(
+                move |_: &SyncMemoizer<u64, u64>, k: &u64| {
+                    barrier.wait();
+                    k * 2
+                },
+            ))
+        };
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let mem = Arc::clone(&mem);
+                thread::spawn(move || mem.lookup(&21))
+            })
+            .collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), 42);
+        }
+        assert_eq!(mem.duplicate_computations(), 1);
+    }
+}